@@ -8,6 +8,7 @@ pub struct BIOSParameterBlock {
     pub(crate) total_sector: u32,
     pub(crate) sector_per_fat: u32,
     pub(crate) root_cluster: u32,
+    pub(crate) fs_info_sector: u16,
     pub(crate) id: u32,
     pub(crate) volume_label: [u8; 11],
     pub(crate) file_system: [u8; 8],
@@ -27,6 +28,25 @@ impl BIOSParameterBlock {
         (self.reserved_sector as usize) * (self.byte_per_sector as usize)
     }
 
+    /// Get FSInfo Offset, None If Volume Has No FSInfo
+    pub(crate) fn fs_info(&self) -> Option<usize> {
+        match self.fs_info_sector {
+            0 | 0xFFFF => None,
+            sector => Some(sector as usize * self.byte_per_sector as usize)
+        }
+    }
+
+    /// Get Count Of Clusters In Data Region, Valid Clusters Are 2..cluster_count() + 2.
+    /// Clusters Which FAT Has No Entry For Are Not Counted
+    pub(crate) fn cluster_count(&self) -> u32 {
+        let data_clusters = (self.total_sector
+            - self.reserved_sector as u32
+            - self.num_fat as u32 * self.sector_per_fat)
+            / self.sector_per_cluster as u32;
+        let fat_entries = self.sector_per_fat * (self.byte_per_sector as u32 / 4);
+        data_clusters.min(fat_entries - 2)
+    }
+
     /// Get sector_per_cluster_usize as usize value
     pub(crate) fn sector_per_cluster_usize(&self) -> usize {
         self.sector_per_cluster as usize
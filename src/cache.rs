@@ -0,0 +1,283 @@
+use core::cell::RefCell;
+use block_device::BlockDevice;
+use crate::BUFFER_SIZE;
+
+/// Define When Cached Sectors Reach The Device
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub enum CacheMode {
+    /// Every Write Goes To The Device At Once
+    WriteThrough,
+    /// Writes Stay In Cache Until Evicted OR Flushed.
+    /// Sectors Reach The Device In LRU Order, Not Written Order, So Power Loss Before
+    /// flush Can Leave Broken OR Crosslinked Chains
+    WriteBack,
+}
+
+#[derive(Copy, Clone)]
+struct Line {
+    address: Option<usize>,
+    data: [u8; BUFFER_SIZE],
+    dirty: bool,
+    /// Clock When Line Was Used Last, 64 Bits So It Never Wraps
+    used: u64,
+}
+
+struct Lines<const N: usize> {
+    lines: [Line; N],
+    clock: u64,
+    hits: u32,
+    misses: u32,
+}
+
+/// LRU Cache Of N Sectors Between Volume And Device, No Alloc.
+/// &SectorCache Implements BlockDevice, So Make Volume From It:
+/// `Volume::new(&cache)`. In WriteBack Mode Call `Volume::flush()` Before Power Off
+pub struct SectorCache<D, const N: usize>
+    where D: BlockDevice {
+    device: D,
+    mode: CacheMode,
+    lines: RefCell<Lines<N>>,
+}
+
+impl<D, const N: usize> SectorCache<D, N>
+    where D: BlockDevice {
+    /// Make Cache Of N Sectors In Front Of Device
+    pub fn new(device: D, mode: CacheMode) -> Self {
+        Self {
+            device,
+            mode,
+            lines: RefCell::new(Lines {
+                lines: [Line {
+                    address: None,
+                    data: [0; BUFFER_SIZE],
+                    dirty: false,
+                    used: 0,
+                }; N],
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// Get Cache Mode
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /// Write All Dirty Sectors To Device, Least Recently Used First
+    pub fn flush(&self) -> Result<(), D::Error> {
+        let mut lines = self.lines.borrow_mut();
+        while let Some(i) = lines.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.dirty)
+            .min_by_key(|(_, l)| l.used)
+            .map(|(i, _)| i) {
+            let line = &mut lines.lines[i];
+            self.device.write(&line.data, line.address.unwrap(), 1)?;
+            line.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Count Of Sectors Found In Cache
+    pub fn hits(&self) -> u32 {
+        self.lines.borrow().hits
+    }
+
+    /// Count Of Sectors Read From Device
+    pub fn misses(&self) -> u32 {
+        self.lines.borrow().misses
+    }
+
+    /// Set Hit AND Miss Counters To Zero
+    pub fn reset_stats(&self) {
+        let mut lines = self.lines.borrow_mut();
+        lines.hits = 0;
+        lines.misses = 0;
+    }
+
+    /// Get Line Which Holds The Sector, Load It On Miss
+    fn load(&self, lines: &mut Lines<N>, address: usize, fill: bool) -> Result<usize, D::Error> {
+        lines.clock += 1;
+        let clock = lines.clock;
+        if let Some(i) = lines.lines.iter().position(|l| l.address == Some(address)) {
+            lines.hits = lines.hits.wrapping_add(1);
+            lines.lines[i].used = clock;
+            return Ok(i);
+        }
+
+        lines.misses = lines.misses.wrapping_add(1);
+        let i = match lines.lines.iter().position(|l| l.address.is_none()) {
+            Some(i) => i,
+            None => (0..N).min_by_key(|&i| lines.lines[i].used).unwrap()
+        };
+        let line = &mut lines.lines[i];
+        if line.dirty {
+            self.device.write(&line.data, line.address.unwrap(), 1)?;
+            line.dirty = false;
+        }
+        line.address = None;
+        if fill { self.device.read(&mut line.data, address, 1)?; }
+        line.address = Some(address);
+        line.used = clock;
+        Ok(i)
+    }
+}
+
+impl<D, const N: usize> BlockDevice for &SectorCache<D, N>
+    where D: BlockDevice {
+    type Error = D::Error;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let mut lines = self.lines.borrow_mut();
+        if number_of_blocks == 1 && N != 0 {
+            let i = self.load(&mut lines, address, true)?;
+            buf[0..BUFFER_SIZE].copy_from_slice(&lines.lines[i].data);
+            return Ok(());
+        }
+
+        // large reads are file data, read them at once and don't keep them
+        self.device.read(buf, address, number_of_blocks)?;
+        let end = address + number_of_blocks * BUFFER_SIZE;
+        for line in lines.lines.iter().filter(|l| l.dirty) {
+            if let Some(a) = line.address.filter(|a| (address..end).contains(a)) {
+                buf[a - address..a - address + BUFFER_SIZE].copy_from_slice(&line.data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let mut lines = self.lines.borrow_mut();
+        if number_of_blocks == 1 && N != 0 {
+            let i = self.load(&mut lines, address, false)?;
+            let line = &mut lines.lines[i];
+            line.data.copy_from_slice(&buf[0..BUFFER_SIZE]);
+            return match self.mode {
+                CacheMode::WriteThrough => self.device.write(&line.data, address, 1)
+                    .inspect_err(|_| line.address = None),
+                CacheMode::WriteBack => {
+                    line.dirty = true;
+                    Ok(())
+                }
+            };
+        }
+
+        self.device.write(buf, address, number_of_blocks)?;
+        let end = address + number_of_blocks * BUFFER_SIZE;
+        for line in lines.lines.iter_mut() {
+            if let Some(a) = line.address.filter(|a| (address..end).contains(a)) {
+                line.data.copy_from_slice(&buf[a - address..a - address + BUFFER_SIZE]);
+                line.dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::Ram;
+
+    const A: usize = 100 * BUFFER_SIZE;
+    const B: usize = 101 * BUFFER_SIZE;
+    const C: usize = 102 * BUFFER_SIZE;
+
+    /// First Byte Of The Sector On The Device, Not In Cache
+    fn on_device(ram: Ram, address: usize) -> u8 {
+        let mut buf = [0; BUFFER_SIZE];
+        ram.read(&mut buf, address, 1).unwrap();
+        buf[0]
+    }
+
+    #[test]
+    fn counters() {
+        let cache = SectorCache::<_, 2>::new(Ram::format(), CacheMode::WriteThrough);
+        let mut buf = [0; BUFFER_SIZE];
+        (&cache).read(&mut buf, A, 1).unwrap();
+        (&cache).read(&mut buf, A, 1).unwrap();
+        (&cache).read(&mut buf, B, 1).unwrap();
+        (&cache).read(&mut buf, A, 1).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+
+        cache.reset_stats();
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let ram = Ram::format();
+        let cache = SectorCache::<_, 2>::new(ram, CacheMode::WriteBack);
+        let mut buf = [0; BUFFER_SIZE];
+        (&cache).write(&[1; BUFFER_SIZE], A, 1).unwrap();
+        (&cache).write(&[2; BUFFER_SIZE], B, 1).unwrap();
+        (&cache).read(&mut buf, A, 1).unwrap();
+        assert_eq!((on_device(ram, A), on_device(ram, B)), (0, 0));
+
+        // B is used least recently, C takes its line
+        (&cache).read(&mut buf, C, 1).unwrap();
+        assert_eq!((on_device(ram, A), on_device(ram, B)), (0, 2));
+        cache.reset_stats();
+        (&cache).read(&mut buf, A, 1).unwrap();
+        assert_eq!((cache.hits(), buf[0]), (1, 1));
+    }
+
+    #[test]
+    fn write_back_flush() {
+        let ram = Ram::format();
+        let cache = SectorCache::<_, 4>::new(ram, CacheMode::WriteBack);
+        (&cache).write(&[1; BUFFER_SIZE], B, 1).unwrap();
+        (&cache).write(&[2; BUFFER_SIZE], A, 1).unwrap();
+
+        // B is flushed first, the write of A fails AND A is kept dirty
+        ram.cut_after(1);
+        assert_eq!(cache.flush(), Err(()));
+        assert_eq!((on_device(ram, A), on_device(ram, B)), (0, 1));
+
+        ram.power_on();
+        assert_eq!(cache.flush(), Ok(()));
+        assert_eq!((on_device(ram, A), on_device(ram, B)), (2, 1));
+        ram.cut_after(0);
+        assert_eq!(cache.flush(), Ok(()));
+    }
+
+    #[test]
+    fn multi_block_read_sees_dirty_sectors() {
+        let ram = Ram::format();
+        ram.write(&[7; 3 * BUFFER_SIZE], A, 3).unwrap();
+        let cache = SectorCache::<_, 4>::new(ram, CacheMode::WriteBack);
+        (&cache).write(&[9; BUFFER_SIZE], B, 1).unwrap();
+
+        let mut buf = [0; 3 * BUFFER_SIZE];
+        (&cache).read(&mut buf, A, 3).unwrap();
+        assert!(buf[0..BUFFER_SIZE].iter().all(|&b| b == 7));
+        assert!(buf[BUFFER_SIZE..2 * BUFFER_SIZE].iter().all(|&b| b == 9));
+        assert!(buf[2 * BUFFER_SIZE..].iter().all(|&b| b == 7));
+
+        // a multi-block write replaces the cached sector
+        (&cache).write(&[5; 3 * BUFFER_SIZE], A, 3).unwrap();
+        let mut sector = [0; BUFFER_SIZE];
+        (&cache).read(&mut sector, B, 1).unwrap();
+        assert_eq!((sector[0], on_device(ram, B)), (5, 5));
+    }
+
+    #[test]
+    fn write_through_holds_no_dirty_sector() {
+        let ram = Ram::format();
+        let cache = SectorCache::<_, 2>::new(ram, CacheMode::WriteThrough);
+        (&cache).write(&[3; BUFFER_SIZE], A, 1).unwrap();
+        assert_eq!(on_device(ram, A), 3);
+
+        // nothing is left to flush, so a dead device isn't touched
+        ram.cut_after(0);
+        assert_eq!(cache.flush(), Ok(()));
+        assert_eq!((&cache).write(&[4; BUFFER_SIZE], B, 1), Err(()));
+        ram.power_on();
+        let mut buf = [0; BUFFER_SIZE];
+        (&cache).read(&mut buf, B, 1).unwrap();
+        assert_eq!((buf[0], on_device(ram, B)), (0, 0));
+    }
+}
@@ -0,0 +1,224 @@
+use core::fmt::Debug;
+
+/// OEM Code Page Which Short Names Are Written In.
+/// Bytes Below 0x80 Are ASCII In Every Code Page
+pub trait OemCodePage: Debug {
+    /// Decode The Char At The Start Of bytes, Return It And Count Of Bytes It Takes.
+    /// bytes Is Never Empty, Bytes Without Mapping Give U+FFFD
+    fn decode(&self, bytes: &[u8]) -> (char, usize);
+
+    /// Encode ch Into buf, Return Count Of Bytes, None If Code Page Has No ch
+    fn encode(&self, ch: char, buf: &mut [u8; 2]) -> Option<usize>;
+}
+
+/// Code Page 437, OEM United States. Used When No Other Is Set
+#[derive(Debug, Copy, Clone)]
+pub struct Cp437;
+
+/// Code Page 850, OEM Multilingual Latin 1
+#[derive(Debug, Copy, Clone)]
+pub struct Cp850;
+
+/// Code Page 936, Simplified Chinese GBK, Double-Byte.
+/// Its Tables Take About 90 KiB Of Flash
+#[cfg(feature = "cp936")]
+#[derive(Debug, Copy, Clone)]
+pub struct Cp936;
+
+const CP437: [u16; 128] = [
+    0x00C7, 0x00FC, 0x00E9, 0x00E2, 0x00E4, 0x00E0, 0x00E5, 0x00E7,
+    0x00EA, 0x00EB, 0x00E8, 0x00EF, 0x00EE, 0x00EC, 0x00C4, 0x00C5,
+    0x00C9, 0x00E6, 0x00C6, 0x00F4, 0x00F6, 0x00F2, 0x00FB, 0x00F9,
+    0x00FF, 0x00D6, 0x00DC, 0x00A2, 0x00A3, 0x00A5, 0x20A7, 0x0192,
+    0x00E1, 0x00ED, 0x00F3, 0x00FA, 0x00F1, 0x00D1, 0x00AA, 0x00BA,
+    0x00BF, 0x2310, 0x00AC, 0x00BD, 0x00BC, 0x00A1, 0x00AB, 0x00BB,
+    0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x2561, 0x2562, 0x2556,
+    0x2555, 0x2563, 0x2551, 0x2557, 0x255D, 0x255C, 0x255B, 0x2510,
+    0x2514, 0x2534, 0x252C, 0x251C, 0x2500, 0x253C, 0x255E, 0x255F,
+    0x255A, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256C, 0x2567,
+    0x2568, 0x2564, 0x2565, 0x2559, 0x2558, 0x2552, 0x2553, 0x256B,
+    0x256A, 0x2518, 0x250C, 0x2588, 0x2584, 0x258C, 0x2590, 0x2580,
+    0x03B1, 0x00DF, 0x0393, 0x03C0, 0x03A3, 0x03C3, 0x00B5, 0x03C4,
+    0x03A6, 0x0398, 0x03A9, 0x03B4, 0x221E, 0x03C6, 0x03B5, 0x2229,
+    0x2261, 0x00B1, 0x2265, 0x2264, 0x2320, 0x2321, 0x00F7, 0x2248,
+    0x00B0, 0x2219, 0x00B7, 0x221A, 0x207F, 0x00B2, 0x25A0, 0x00A0,
+];
+
+const CP850: [u16; 128] = [
+    0x00C7, 0x00FC, 0x00E9, 0x00E2, 0x00E4, 0x00E0, 0x00E5, 0x00E7,
+    0x00EA, 0x00EB, 0x00E8, 0x00EF, 0x00EE, 0x00EC, 0x00C4, 0x00C5,
+    0x00C9, 0x00E6, 0x00C6, 0x00F4, 0x00F6, 0x00F2, 0x00FB, 0x00F9,
+    0x00FF, 0x00D6, 0x00DC, 0x00F8, 0x00A3, 0x00D8, 0x00D7, 0x0192,
+    0x00E1, 0x00ED, 0x00F3, 0x00FA, 0x00F1, 0x00D1, 0x00AA, 0x00BA,
+    0x00BF, 0x00AE, 0x00AC, 0x00BD, 0x00BC, 0x00A1, 0x00AB, 0x00BB,
+    0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x00C1, 0x00C2, 0x00C0,
+    0x00A9, 0x2563, 0x2551, 0x2557, 0x255D, 0x00A2, 0x00A5, 0x2510,
+    0x2514, 0x2534, 0x252C, 0x251C, 0x2500, 0x253C, 0x00E3, 0x00C3,
+    0x255A, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256C, 0x00A4,
+    0x00F0, 0x00D0, 0x00CA, 0x00CB, 0x00C8, 0x0131, 0x00CD, 0x00CE,
+    0x00CF, 0x2518, 0x250C, 0x2588, 0x2584, 0x00A6, 0x00CC, 0x2580,
+    0x00D3, 0x00DF, 0x00D4, 0x00D2, 0x00F5, 0x00D5, 0x00B5, 0x00FE,
+    0x00DE, 0x00DA, 0x00DB, 0x00D9, 0x00FD, 0x00DD, 0x00AF, 0x00B4,
+    0x00AD, 0x00B1, 0x2017, 0x00BE, 0x00B6, 0x00A7, 0x00F7, 0x00B8,
+    0x00B0, 0x00A8, 0x00B7, 0x00B9, 0x00B3, 0x00B2, 0x25A0, 0x00A0,
+];
+
+impl OemCodePage for Cp437 {
+    fn decode(&self, bytes: &[u8]) -> (char, usize) {
+        decode_single(&CP437, bytes[0])
+    }
+
+    fn encode(&self, ch: char, buf: &mut [u8; 2]) -> Option<usize> {
+        encode_single(&CP437, ch, buf)
+    }
+}
+
+impl OemCodePage for Cp850 {
+    fn decode(&self, bytes: &[u8]) -> (char, usize) {
+        decode_single(&CP850, bytes[0])
+    }
+
+    fn encode(&self, ch: char, buf: &mut [u8; 2]) -> Option<usize> {
+        encode_single(&CP850, ch, buf)
+    }
+}
+
+fn decode_single(table: &[u16; 128], byte: u8) -> (char, usize) {
+    if byte < 0x80 { return (byte as char, 1); }
+    (char::from_u32(table[byte as usize - 0x80] as u32).unwrap_or('\u{FFFD}'), 1)
+}
+
+fn encode_single(table: &[u16; 128], ch: char, buf: &mut [u8; 2]) -> Option<usize> {
+    if ch.is_ascii() {
+        buf[0] = ch as u8;
+        return Some(1);
+    }
+    let i = table.iter().position(|&c| c as u32 == ch as u32)?;
+    buf[0] = 0x80 + i as u8;
+    Some(1)
+}
+
+/// Unicode Of Double-Byte Chars, Index Is (lead - 0x81) * 191 + (trail - 0x40),
+/// 0 If Unmapped. Made By codepage/cp936.py, Little Endian u16
+#[cfg(feature = "cp936")]
+const CP936: &[u8] = include_bytes!("codepage/cp936.bin");
+
+/// Indices Of Mapped Chars In CP936 Sorted By Unicode, For encode. Made By codepage/cp936.py
+#[cfg(feature = "cp936")]
+const CP936_REV: &[u8] = include_bytes!("codepage/cp936_rev.bin");
+
+#[cfg(feature = "cp936")]
+impl Cp936 {
+    fn unicode(index: usize) -> u16 {
+        u16::from_le_bytes([CP936[index * 2], CP936[index * 2 + 1]])
+    }
+
+    fn sorted(i: usize) -> usize {
+        u16::from_le_bytes([CP936_REV[i * 2], CP936_REV[i * 2 + 1]]) as usize
+    }
+}
+
+#[cfg(feature = "cp936")]
+impl OemCodePage for Cp936 {
+    fn decode(&self, bytes: &[u8]) -> (char, usize) {
+        match bytes {
+            [b, ..] if *b < 0x80 => (*b as char, 1),
+            [0x80, ..] => ('\u{20AC}', 1),
+            [lead @ 0x81..=0xFE, trail @ 0x40..=0xFE, ..] => {
+                let unit = Self::unicode((*lead as usize - 0x81) * 191 + (*trail as usize - 0x40));
+                match char::from_u32(unit as u32) {
+                    Some(ch) if unit != 0 => (ch, 2),
+                    _ => ('\u{FFFD}', 2)
+                }
+            }
+            _ => ('\u{FFFD}', 1)
+        }
+    }
+
+    fn encode(&self, ch: char, buf: &mut [u8; 2]) -> Option<usize> {
+        if ch.is_ascii() {
+            buf[0] = ch as u8;
+            return Some(1);
+        }
+        if ch == '\u{20AC}' {
+            buf[0] = 0x80;
+            return Some(1);
+        }
+        if ch as u32 > 0xFFFF { return None; }
+        let (mut low, mut high) = (0, CP936_REV.len() / 2);
+        while low < high {
+            let mid = (low + high) / 2;
+            if Self::unicode(Self::sorted(mid)) < ch as u16 { low = mid + 1; } else { high = mid; }
+        }
+        let index = Some(low).filter(|&i| i < CP936_REV.len() / 2).map(Self::sorted)
+            .filter(|&index| Self::unicode(index) == ch as u16)?;
+        buf[0] = (index / 191 + 0x81) as u8;
+        buf[1] = (index % 191 + 0x40) as u8;
+        Some(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(page: &dyn OemCodePage) {
+        let mut buf = [0; 2];
+        for byte in 0..=0xFF_u8 {
+            let (ch, len) = page.decode(&[byte]);
+            assert_eq!(len, 1);
+            assert_eq!(page.encode(ch, &mut buf), Some(1));
+            assert_eq!(buf[0], byte);
+        }
+    }
+
+    #[test]
+    fn cp437() {
+        round_trip(&Cp437);
+        assert_eq!(Cp437.decode(&[0x82]), ('é', 1));
+        assert_eq!(Cp437.encode('€', &mut [0; 2]), None);
+    }
+
+    #[test]
+    fn cp850() {
+        round_trip(&Cp850);
+        assert_eq!(Cp850.decode(&[0x90]), ('É', 1));
+        assert_eq!(Cp850.decode(&[0xD5]), ('ı', 1));
+    }
+
+    #[cfg(feature = "cp936")]
+    #[test]
+    fn cp936() {
+        let mut buf = [0; 2];
+        assert_eq!(Cp936.decode(b"a"), ('a', 1));
+        assert_eq!(Cp936.decode(&[0x80]), ('€', 1));
+        assert_eq!(Cp936.encode('€', &mut buf), Some(1));
+        assert_eq!(buf[0], 0x80);
+
+        assert_eq!(Cp936.decode(&[0xD6, 0xD0]), ('中', 2));
+        assert_eq!(Cp936.encode('中', &mut buf), Some(2));
+        assert_eq!(buf, [0xD6, 0xD0]);
+
+        // lead byte without trail byte, AND trail byte out of range
+        assert_eq!(Cp936.decode(&[0xD6]), ('\u{FFFD}', 1));
+        assert_eq!(Cp936.decode(&[0xD6, 0x20]), ('\u{FFFD}', 1));
+        assert_eq!(Cp936.decode(&[0xFF]), ('\u{FFFD}', 1));
+        assert_eq!(Cp936.encode('\u{1F600}', &mut buf), None);
+        assert_eq!(Cp936.encode('\u{FFFF}', &mut buf), None);
+        assert_eq!(Cp936.encode('\u{00A0}', &mut buf), None);
+    }
+
+    #[cfg(feature = "cp936")]
+    #[test]
+    fn cp936_every_char_round_trips() {
+        let mut buf = [0; 2];
+        for lead in 0x81..=0xFE_u8 {
+            for trail in 0x40..=0xFE_u8 {
+                let (ch, len) = Cp936.decode(&[lead, trail]);
+                if ch == '\u{FFFD}' { continue; }
+                assert_eq!((len, Cp936.encode(ch, &mut buf)), (2, Some(2)));
+                assert_eq!(buf, [lead, trail]);
+            }
+        }
+    }
+}
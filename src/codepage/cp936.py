@@ -0,0 +1,64 @@
+#!/usr/bin/env python3
+"""Make cp936.bin AND cp936_rev.bin Which src/codepage.rs Includes.
+
+cp936.bin: Unicode Of Each Double-Byte Char, Index Is (lead - 0x81) * 191 + (trail - 0x40),
+0 If Unmapped, Little Endian u16.
+cp936_rev.bin: Indices Of Mapped Chars In cp936.bin Sorted By Unicode, Little Endian u16,
+So Cp936::encode Can Binary Search.
+
+Usage: python3 cp936.py [CP936.TXT]
+
+Without An Argument The Mapping Comes From The cp936 Codec Of Python.
+Otherwise It Is Read From The Windows Table Of The Unicode Consortium,
+https://www.unicode.org/Public/MAPPINGS/VENDORS/MICSFT/WINDOWS/CP936.TXT
+"""
+
+import os
+import struct
+import sys
+
+LEADS = range(0x81, 0xFF)
+TRAILS = range(0x40, 0xFF)
+
+
+def from_codec():
+    table = {}
+    for lead in LEADS:
+        for trail in TRAILS:
+            try:
+                ch = bytes([lead, trail]).decode('cp936')
+            except UnicodeDecodeError:
+                continue
+            table[(lead, trail)] = ord(ch)
+    return table
+
+
+def from_file(path):
+    table = {}
+    with open(path, encoding='latin-1') as f:
+        for line in f:
+            columns = line.split('#')[0].split()
+            # single bytes AND unmapped codes have no place in cp936.bin
+            if len(columns) != 2 or len(columns[0]) != 6:
+                continue
+            code, unicode = int(columns[0], 16), int(columns[1], 16)
+            table[(code >> 8, code & 0xFF)] = unicode
+    return table
+
+
+def main():
+    table = from_file(sys.argv[1]) if len(sys.argv) > 1 else from_codec()
+    units = [table.get((lead, trail), 0) for lead in LEADS for trail in TRAILS]
+    if any(unit > 0xFFFF for unit in units):
+        sys.exit('cp936.bin only holds chars of the BMP')
+    mapped = sorted((unit, index) for index, unit in enumerate(units) if unit != 0)
+
+    here = os.path.dirname(os.path.abspath(__file__))
+    with open(os.path.join(here, 'cp936.bin'), 'wb') as f:
+        f.write(struct.pack('<%dH' % len(units), *units))
+    with open(os.path.join(here, 'cp936_rev.bin'), 'wb') as f:
+        f.write(struct.pack('<%dH' % len(mapped), *(index for _, index in mapped)))
+
+
+if __name__ == '__main__':
+    main()
@@ -1,17 +1,33 @@
 use block_device::BlockDevice;
 use crate::bpb::BIOSParameterBlock;
-use crate::entry::Entry;
+use crate::entry::{Entry, DirEntry};
 use crate::BUFFER_SIZE;
 use crate::tool::{
-    is_illegal,
+    read_le_u16,
+    read_le_u32,
+    check_name,
     sfn_or_lfn,
     get_count_of_lfn,
-    get_lfn_index,
+    MAX_NAME_UNITS,
     generate_checksum,
+    wildcard_match,
+    BasisName,
+    fnv1a,
 };
 use crate::entry::NameType;
-use crate::file::File;
-use crate::fat::FAT;
+use crate::file::{
+    File,
+    FileError,
+    OpenOptions,
+    WriteType,
+};
+use crate::fat::{
+    FAT,
+    FATError,
+};
+use crate::extent::ExtentCache;
+use crate::codepage::OemCodePage;
+use crate::index::{DirIndex, name_hash};
 
 /// Define DirError
 #[derive(Debug, PartialOrd, PartialEq)]
@@ -19,8 +35,66 @@ pub enum DirError {
     NoMatchDir,
     NoMatchFile,
     IllegalChar,
-    DirHasExist,
-    FileHasExist,
+    ControlChar,
+    ReservedName,
+    EmptyName,
+    AlreadyExists,
+    IsADirectory,
+    InvalidOptions,
+    DiskFull,
+    CorruptChain,
+    WriteFailed,
+    NameTooLong,
+    NoShortAlias,
+    TooDeep,
+    DirNotEmpty,
+    InvalidCursor,
+    PathPattern,
+}
+
+impl From<FATError> for DirError {
+    fn from(e: FATError) -> Self {
+        match e {
+            FATError::DiskFull => DirError::DiskFull,
+            FATError::CorruptChain => DirError::CorruptChain,
+            FATError::WriteFailed => DirError::WriteFailed,
+        }
+    }
+}
+
+impl From<FileError> for DirError {
+    fn from(e: FileError) -> Self {
+        match e {
+            FileError::DiskFull => DirError::DiskFull,
+            FileError::CorruptChain => DirError::CorruptChain,
+            _ => DirError::WriteFailed,
+        }
+    }
+}
+
+/// Name Of The Hidden Temp File In Root Dir Which replace_file Writes To,
+/// Only Taken For The Temp File If It Has Hidden AND System Attributes
+pub(crate) const REPLACE_TEMP: &str = "~FSREPL~.$$$";
+
+/// Hidden AND System Attributes
+const HIDDEN_SYSTEM: u8 = 0x02 | 0x04;
+
+/// Depth Of Stack For Freeing A Deleted Dir Tree, Deeper Trees Are Freed In Parts
+const DELETE_DEPTH: usize = 8;
+
+/// Largest "~N" Tail Of Short Alias, "X~999999" Still Keeps One Name Byte
+const MAX_TAIL: u32 = 999_999;
+
+/// Get N Of Short Name With "~N" Tail
+fn parse_tail(sfn: &[u8; 11]) -> Option<u32> {
+    let at = sfn[0..8].iter().rposition(|&b| b == b'~')?;
+    let digits = &sfn[at + 1..8];
+    let end = digits.iter().position(|&b| b == 0x20).unwrap_or(digits.len());
+    if end == 0 || digits[end..].iter().any(|&b| b != 0x20) { return None; }
+    digits[0..end].iter().try_fold(0u32, |n, &b| match b {
+        b'0'..=b'9' => Some(n * 10 + (b - b'0') as u32),
+        _ => None,
+    })
 }
 
 /// Define Operation Type
@@ -30,56 +104,166 @@ pub enum OpType {
     File,
 }
 
+/// Dir, N Is The Capacity Of Name Index, No Index By Default
 #[derive(Debug, Copy, Clone)]
-pub struct Dir<'a, T>
+pub struct Dir<'a, T, const N: usize = 0>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
     pub(crate) device: T,
     pub(crate) bpb: &'a BIOSParameterBlock,
     pub(crate) detail: Entry,
     pub(crate) fat: FAT<T>,
+    pub(crate) code_page: &'static dyn OemCodePage,
+    pub(crate) index: DirIndex<N>,
 }
 
-impl<'a, T> Dir<'a, T>
+impl<'a, T, const N: usize> Dir<'a, T, N>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
-    /// Delete Dir
+    /// Use Name Index Which Holds M Names At Most, Built On The First Create, Open OR Delete.
+    /// Then Lookups In Big Dir Don't Need To Scan It.
+    /// Changes Made Through Other Dir<T> Are Found On Disk, The Index Is Built Again Then
+    pub fn with_index<const M: usize>(self) -> Dir<'a, T, M> {
+        Dir::<T, M> {
+            device: self.device,
+            bpb: self.bpb,
+            detail: self.detail,
+            fat: self.fat,
+            code_page: self.code_page,
+            index: DirIndex::new(),
+        }
+    }
+
+    /// Get Name Index
+    pub fn index(&self) -> &DirIndex<N> {
+        &self.index
+    }
+
+    /// Delete Dir AND Everything In It
+    #[deprecated(note = "use remove_dir, OR remove_dir_all to delete what the dir holds too")]
     pub fn delete_dir(&mut self, dir: &str) -> Result<(), DirError> {
-        self.delete(dir, OpType::Dir)
+        self.remove_dir_all(dir)
+    }
+
+    /// Delete Empty Dir, DirError::DirNotEmpty If It Holds Anything Besides "." AND ".."
+    pub fn remove_dir(&mut self, dir: &str) -> Result<(), DirError> {
+        self.delete(dir, OpType::Dir, false)
+    }
+
+    /// Delete Dir AND Everything In It, At Any Depth
+    pub fn remove_dir_all(&mut self, dir: &str) -> Result<(), DirError> {
+        self.delete(dir, OpType::Dir, true)
     }
 
     /// Delete File
     pub fn delete_file(&mut self, file: &str) -> Result<(), DirError> {
-        self.delete(file, OpType::File)
+        self.delete(file, OpType::File, false)
     }
 
     /// Create Dir
     pub fn create_dir(&mut self, dir: &str) -> Result<(), DirError> {
-        self.create(dir, OpType::Dir)
+        self.create(dir, OpType::Dir, 0)
     }
 
     /// Create File
     pub fn create_file(&mut self, file: &str) -> Result<(), DirError> {
-        self.create(file, OpType::File)
+        self.create(file, OpType::File, 0)
+    }
+
+    /// Create File With Hidden AND System Attributes In The Same Write,
+    /// For Files This Crate Keeps In Root Dir
+    pub(crate) fn create_hidden_file(&mut self, file: &str) -> Result<(), DirError> {
+        self.create(file, OpType::File, HIDDEN_SYSTEM)
+    }
+
+    /// Delete Temp File Which replace_file Left When Power Was Lost.
+    /// A File With The Same Name But Without Hidden AND System Attributes Is Kept
+    pub(crate) fn delete_replace_temp(&mut self) -> Result<(), DirError> {
+        match self.exist(REPLACE_TEMP) {
+            Some(di) if di.is_hidden_system() => self.delete_file(REPLACE_TEMP),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace Content Of File, Readers See Whole Old OR Whole New Content.
+    /// File Is Created Empty First If Missing
+    pub fn replace_file(&mut self, file: &str, contents: &[u8]) -> Result<(), DirError> {
+        self.replace_file_with(file, |f| f.write(contents, WriteType::OverWritten))
+    }
+
+    /// Replace Content Of File With What write Writes To A Hidden Temp File In Root Dir.
+    /// Dir Item Of File Is Switched To The Temp Chain With One Sector Write,
+    /// Then The Old Chain Is Freed. Volume::new Deletes Temp File Left By Power Loss.
+    /// Err(AlreadyExists) If Root Dir Has A Visible File Named Like The Temp File
+    pub fn replace_file_with<F>(&mut self, file: &str, write: F) -> Result<(), DirError>
+        where F: FnOnce(&mut File<'a, T>) -> Result<(), FileError> {
+        let file = check_name(file)?;
+        match self.exist(file) {
+            Some(di) if di.is_dir() => return Err(DirError::IsADirectory),
+            Some(_) => (),
+            None => self.create_file(file)?
+        }
+
+        let mut root = self.root();
+        root.delete_replace_temp()?;
+        root.create_hidden_file(REPLACE_TEMP)?;
+        let mut temp = root.open_file(REPLACE_TEMP)?;
+        if let Err(e) = write(&mut temp) {
+            root.delete_file(REPLACE_TEMP)?;
+            return Err(e.into());
+        }
+
+        // the temp item goes first, so its chain is never referenced twice
+        let mut iter = DirIter::new(root.device, root.fat, root.bpb);
+        let (_, lfn_count) = root.find_item(&mut iter, REPLACE_TEMP).ok_or(DirError::NoMatchFile)?;
+        Self::delete_items(&mut iter, lfn_count)?;
+
+        let mut target = self.open_file(file)?;
+        target.switch_chain(temp.detail.cluster(), temp.length())?;
+        Ok(())
     }
 
     /// Open File, Return File<T> Type
     pub fn open_file(&self, file: &str) -> Result<File<'a, T>, DirError> {
-        if is_illegal(file) { return Err(DirError::IllegalChar); }
-        match self.exist(file) {
-            None => Err(DirError::NoMatchFile),
+        let file = check_name(file)?;
+        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        match self.exist_iter(&mut iter, file) {
+            None => {
+                iter.check()?;
+                Err(DirError::NoMatchFile)
+            }
             Some(di) => if di.is_file() {
-                let fat = FAT::new(di.cluster(),
-                                   self.device,
-                                   self.bpb.fat1());
-                Ok(File::<T> {
-                    device: self.device,
-                    bpb: self.bpb,
-                    dir_cluster: self.detail.cluster(),
-                    detail: di,
-                    fat,
-                })
+                Ok(self.file(di, iter.item_position() - 1, OpenOptions::read_write()))
             } else {
+                Err(DirError::IsADirectory)
+            }
+        }
+    }
+
+    /// Open File With OpenOptions, Create OR Truncate It As Needed.
+    /// The Name Is Looked Up Only Once, Return File<T> Type.
+    /// Err(InvalidOptions) If Neither read Nor write Is Set, OR A Change Is Asked Without write
+    pub fn open(&mut self, file: &str, options: &OpenOptions) -> Result<File<'a, T>, DirError> {
+        if !options.is_valid() { return Err(DirError::InvalidOptions); }
+        let file = check_name(file)?;
+        self.refresh_index()?;
+
+        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        match self.exist_iter(&mut iter, file) {
+            Some(_) if options.create_new => Err(DirError::AlreadyExists),
+            Some(di) if di.is_dir() => Err(DirError::IsADirectory),
+            Some(di) => {
+                let mut file = self.file(di, iter.item_position() - 1, *options);
+                if options.truncate && di.length().unwrap() != 0 { file.truncate()?; }
+                Ok(file)
+            }
+            None if options.create || options.create_new => {
+                iter.check()?;
+                let di = self.insert(&mut iter, file, OpType::File, 0)?;
+                Ok(self.file(di, iter.item_position() - 1, *options))
+            }
+            None => {
+                iter.check()?;
                 Err(DirError::NoMatchFile)
             }
         }
@@ -87,210 +271,482 @@ impl<'a, T> Dir<'a, T>
 
     /// Cd Dir, Return Dir<T> Type
     pub fn cd(&self, dir: &str) -> Result<Dir<'a, T>, DirError> {
-        if is_illegal(dir) { return Err(DirError::IllegalChar); }
-        match self.exist(dir) {
-            None => Err(DirError::NoMatchDir),
+        let dir = check_name(dir)?;
+        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        match self.exist_iter(&mut iter, dir) {
+            None => {
+                iter.check()?;
+                Err(DirError::NoMatchDir)
+            }
             Some(di) => if di.is_dir() {
-                let fat = FAT::new(di.cluster(),
-                                   self.device,
-                                   self.bpb.fat1());
-                Ok(Self {
-                    device: self.device,
-                    bpb: self.bpb,
-                    detail: di,
-                    fat,
-                })
+                Ok(self.sub_dir(di))
             } else {
                 Err(DirError::NoMatchDir)
             }
         }
     }
 
+    /// Walk Dir Tree Depth-First, Dirs Before Their Contents.
+    /// The Stack Of D Dirs Is Held In Walk<T>, So Entries Deeper Than D - 1 Dirs
+    /// Give DirError::TooDeep AND Are Skipped
+    pub fn walk<const D: usize>(&self) -> Walk<'a, T, D> {
+        Walk::new(self)
+    }
+
+    /// Find Entries Of Dir Whose Name Matches DOS-Style pattern, Like "FIRMWARE*.BIN".
+    /// '*' Is Any Run Of Chars AND '?' Is One Char, "*." Is Names Without Extension.
+    /// Long Names Match Case-Insensitively.
+    /// Gives Err(PathPattern) For A pattern With '/' OR "**",
+    /// Use walk::<D>().glob() To Search D - 1 Dirs Below Dir
+    pub fn find<'p>(&self, pattern: &'p str) -> Find<'a, 'p, T, 1> {
+        let mut find = self.walk::<1>().glob(pattern);
+        if pattern.contains('/') || pattern == "**" { find.error = Some(DirError::PathPattern); }
+        find
+    }
+
+    /// Open File From Entry Got By walk OR find, Without Looking It Up Again
+    pub fn open_entry(&self, entry: &DirEntry) -> Result<File<'a, T>, DirError> {
+        if !entry.is_file() { return Err(DirError::IsADirectory); }
+        let mut file = self.file(entry.entry(), entry.position(), OpenOptions::read_write());
+        file.dir_cluster = entry.dir_cluster();
+        Ok(file)
+    }
+
+    /// Cd Dir From Entry Got By walk OR find, Without Looking It Up Again
+    pub fn cd_entry(&self, entry: &DirEntry) -> Result<Dir<'a, T>, DirError> {
+        if !entry.is_dir() { return Err(DirError::NoMatchDir); }
+        Ok(self.sub_dir(entry.entry()))
+    }
+
+    /// List Entries Of Dir From The Start, Entries::cursor() Tells Where To Resume
+    pub fn entries(&self) -> Entries<'a, T> {
+        Entries::new(self, DirIter::new(self.device, self.fat, self.bpb), DirCursor::default(), false)
+    }
+
+    /// List Entries Of Dir After The One cursor Was Taken At, Without Scanning From The Start.
+    /// Err If cursor Is Not In Dir. Entries::is_changed() Tells If That Entry Is Gone
+    pub fn entries_from(&self, cursor: &DirCursor) -> Result<Entries<'a, T>, DirError> {
+        if *cursor == DirCursor::default() { return Ok(self.entries()); }
+        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        if !iter.seek_cursor(cursor) {
+            iter.check()?;
+            return Err(DirError::InvalidCursor);
+        }
+        let changed = iter.cursor().check != cursor.check;
+        iter.offset_index();
+        Ok(Entries::new(self, iter, *cursor, changed))
+    }
+
+    /// Count Files, Dirs AND Bytes In Dir Tree, D Is The Depth Of Stack Like walk
+    pub fn du<const D: usize>(&self) -> Result<DiskUsage, DirError> {
+        let cluster_size = self.bpb.sector_per_cluster_usize() * BUFFER_SIZE;
+        let mut usage = DiskUsage::default();
+        let mut walk = self.walk::<D>();
+        while let Some(item) = walk.next_entry() {
+            let (_, _, d) = item?;
+            if d.is_dir() { usage.dirs += 1; } else { usage.files += 1; }
+            usage.bytes += d.length();
+
+            let cluster = d.entry().cluster();
+            if cluster == 0 { continue; }
+            let mut fat = FAT::new(cluster, self.device, self.bpb);
+            usage.allocated += fat.by_ref().count() * cluster_size;
+            fat.check()?;
+        }
+        Ok(usage)
+    }
+
     /// Check if file or dir is exist or not, Return Option Type
     pub fn exist(&self, value: &str) -> Option<Entry> {
         let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        self.exist_iter(&mut iter, value)
+    }
+
+    /// Check if file or dir is exist or not through DirIter<T>, Return Option Type
+    pub fn exist_iter(&self, iter: &mut DirIter<'a, T>, value: &str) -> Option<Entry> {
+        let value = check_name(value).ok()?;
+        self.lookup(iter, value).map(|(di, _)| di)
+    }
 
-        match sfn_or_lfn(value) {
-            NameType::SFN => iter.find(|d| d.sfn_equal(value)),
-            NameType::LFN => self.find_lfn(&mut iter, value),
+    /// Find Item Like find_item, Through The Index If It Is Built.
+    /// Positions From Index Are Checked On Disk, Dir Is Scanned If They Are Stale
+    fn lookup(&self, iter: &mut DirIter<'a, T>, value: &str) -> Option<(Entry, usize)> {
+        if self.index.is_built() {
+            for (start, len) in self.index.get(name_hash(value.encode_utf16())) {
+                let mut at = DirIter::new(self.device, self.fat, self.bpb);
+                if !at.seek(start) { continue; }
+                match self.find_item(&mut at, value) {
+                    Some(found) if at.item_position() == start + len => {
+                        *iter = at;
+                        return Some(found);
+                    }
+                    _ => continue,
+                }
+            }
+            // a name missing from a complete index is not in dir, unless items were added behind it
+            if self.index.is_complete() {
+                let mut at = DirIter::new(self.device, self.fat, self.bpb);
+                if at.seek(self.index.end()) && at.at_end() {
+                    *iter = at;
+                    return None;
+                }
+            }
         }
+        self.find_item(iter, value)
     }
 
-    /// Check if file or dir is exist or not through DirIter<T>, Return Option Type
-    pub fn exist_iter(&self, iter: &mut DirIter<T>, value: &str) -> Option<Entry> {
-        match sfn_or_lfn(value) {
-            NameType::SFN => iter.find(|d| d.sfn_equal(value)),
-            NameType::LFN => self.find_lfn(iter, value),
+    /// Build Index If It Is Missing, Worn OR Items Were Added Behind It
+    fn refresh_index(&mut self) -> Result<(), DirError> {
+        if N == 0 { return Ok(()); }
+        if self.index.is_built() && !self.index.is_worn() {
+            let mut at = DirIter::new(self.device, self.fat, self.bpb);
+            if at.seek(self.index.end()) && at.at_end() { return Ok(()); }
+        }
+
+        self.index.reset();
+        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        let mut entry = DirEntry::new();
+        while let Some(item) = iter.next() {
+            if entry.collect(item, self.detail.cluster(), iter.item_position() - 1, self.code_page) {
+                let start = iter.item_position() - entry.items();
+                self.index.insert(name_hash(entry.name_utf16().iter().copied()), start, entry.items());
+                if let Some((units, n)) = entry.alias_utf16(self.code_page) {
+                    self.index.insert(name_hash(units[0..n].iter().copied()), start, entry.items());
+                }
+            }
+        }
+        if let Err(e) = iter.check() {
+            self.index = DirIndex::new();
+            return Err(e);
         }
+        self.index.set_end(iter.item_position());
+        Ok(())
     }
 
-    /// Find Long File Name Item, Return Option Type
-    fn find_lfn(&self, iter: &mut DirIter<T>, value: &str) -> Option<Entry> {
+    /// Find Item Whose Long Name OR Short Name Is value, Like Windows Does.
+    /// Return It With Count Of LFN Items Before It.
+    /// If None, The Iterator Stops At The End Of Dir
+    fn find_item(&self, iter: &mut DirIter<T>, value: &str) -> Option<(Entry, usize)> {
         let count = get_count_of_lfn(value);
-        let mut index = get_lfn_index(value, count);
-        let mut has_match = true;
-
-        let result = iter.find(|d| {
-            if d.is_lfn()
-                && d.count_of_name().unwrap() == count
-                && d.is_name_end().unwrap()
-                && d.lfn_equal(&value[index..]) {
-                true
-            } else { false }
-        });
-
-        if let Some(_) = result {
-            for c in (1..count).rev() {
-                let value = &value[0..index];
-                index = get_lfn_index(value, c);
+        // LFN items before current one, next order they should have, if they hold value
+        let (mut run, mut expect, mut matched) = (0, 0, false);
 
-                let next = iter.next().unwrap();
-                if next.lfn_equal(&value[index..]) {
-                    continue;
+        loop {
+            let d = iter.next()?;
+            if d.is_deleted() || d.is_volume_label() {
+                run = 0;
+            } else if d.is_lfn() {
+                let ord = d.count_of_name().unwrap();
+                if d.is_name_end().unwrap() {
+                    run = 1;
+                    matched = ord == count && d.lfn_equal(value);
                 } else {
-                    has_match = false;
-                    break;
+                    run += 1;
+                    matched = matched && ord == expect && d.lfn_equal(value);
                 }
+                expect = ord.saturating_sub(1);
+            } else {
+                let long_match = run != 0 && matched && expect == 0;
+                if long_match || d.sfn_equal(value, self.code_page) { return Some((d, run)); }
+                run = 0;
             }
         }
+    }
 
-        if has_match { iter.next() } else { None }
+    /// Basic Create Function, attribute Bits Are Set In The Short Item
+    fn create(&mut self, value: &str, create_type: OpType, attribute: u8) -> Result<(), DirError> {
+        let value = check_name(value)?;
+        self.refresh_index()?;
+
+        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        if self.exist_iter(&mut iter, value).is_some() { return Err(DirError::AlreadyExists); }
+        iter.check()?;
+
+        self.insert(&mut iter, value, create_type, attribute)?;
+        Ok(())
     }
 
-    /// Basic Create Function
-    fn create(&mut self, value: &str, create_type: OpType) -> Result<(), DirError> {
-        if is_illegal(value) { return Err(DirError::IllegalChar); }
-        if let Some(_) = self.exist(value) {
-            return match create_type {
-                OpType::Dir => Err(DirError::DirHasExist),
-                OpType::File => Err(DirError::FileHasExist)
-            };
+    /// Write Items Of The New File OR Dir Where The Iterator Stops, Return The Short Item.
+    /// The Cluster Is Allocated And Filled Before Items Point To It.
+    /// If Dir Can't Grow, Written Items And The Cluster Are Released
+    fn insert(&mut self, iter: &mut DirIter<T>, value: &str, create_type: OpType, attribute: u8)
+              -> Result<Entry, DirError> {
+        if value.encode_utf16().count() > MAX_NAME_UNITS { return Err(DirError::NameTooLong); }
+        let alias = match sfn_or_lfn(value) {
+            NameType::SFN => None,
+            NameType::LFN => Some(self.short_alias(value)?),
+        };
+        let blank_cluster = self.fat.allocate(1, None)?;
+        let mut sfn = match alias {
+            None => Entry::new_sfn(blank_cluster, value, create_type),
+            Some(alias) => Entry::new_sfn_bytes(blank_cluster, &alias, create_type),
+        };
+        sfn.set_attribute(attribute);
+        let mut written = 0;
+        let result = match create_type {
+            OpType::Dir => self.clean_cluster_data(blank_cluster)
+                .and_then(|_| self.add_dot_item(blank_cluster)),
+            OpType::File => Ok(()),
+        }.and_then(|_| self.insert_items(iter, value, sfn, alias.is_some(), &mut written));
+        if result.is_err() {
+            // items are appended at the end of dir, so the slots were blank
+            for _ in 0..written {
+                iter.previous();
+                iter.update_item(&[0; 32]);
+                let _ = iter.update();
+            }
+            let _ = self.fat.free(blank_cluster);
+        } else {
+            let start = iter.item_position() - written;
+            self.index.insert(name_hash(value.encode_utf16()), start, written);
+            if let Some((units, n)) = alias.and(result.as_ref().ok()).and_then(|di| di.get_sfn(self.code_page)) {
+                self.index.insert(name_hash(units[0..n].iter().copied()), start, written);
+            }
+            self.index.set_end(iter.item_position());
         }
+        let info = iter.update_fs_info().and(self.fat.update_fs_info());
+        let di = result?;
+        info?;
+        Ok(di)
+    }
 
-        let blank_cluster = self.fat.blank_cluster();
-        self.fat.write(blank_cluster, 0x0FFFFFFF);
-
-        match sfn_or_lfn(value) {
-            NameType::SFN => {
-                let di = Entry::new_sfn(blank_cluster,
-                                        value,
-                                        create_type);
-                self.write_directory_item(di);
+    /// Append LFN Items Of value If long, Then The Short Item sfn.
+    /// Count Of Written Items Is Kept In written
+    fn insert_items(&self, iter: &mut DirIter<T>, value: &str, sfn: Entry, long: bool,
+                    written: &mut usize) -> Result<Entry, FATError> {
+        match long {
+            false => {
+                iter.append_item(&sfn.bytes())?;
+                *written += 1;
+                Ok(sfn)
             }
-            NameType::LFN => {
-                let sfn = "unsupported".as_bytes();
-                let check_sum = generate_checksum(sfn);
+            true => {
+                let check_sum = generate_checksum(&sfn.bytes()[0..11]);
                 let count = get_count_of_lfn(value);
-                let mut lfn_index = get_lfn_index(value, count);
 
                 let di = Entry::new_lfn((count as u8) | (1 << 6),
                                         check_sum,
-                                        &value[lfn_index..]);
+                                        value);
 
-                self.write_directory_item(di);
+                iter.append_item(&di.bytes())?;
+                *written += 1;
 
                 for c in (1..count).rev() {
-                    let value = &value[0..lfn_index];
-                    lfn_index = get_lfn_index(value, c);
                     let di = Entry::new_lfn(c as u8,
                                             check_sum,
-                                            &value[lfn_index..]);
-                    self.write_directory_item(di);
+                                            value);
+                    iter.append_item(&di.bytes())?;
+                    *written += 1;
                 }
 
-                let di = Entry::new_sfn_bytes(blank_cluster,
-                                              sfn,
-                                              create_type);
-                self.write_directory_item(di);
+                iter.append_item(&sfn.bytes())?;
+                *written += 1;
+                Ok(sfn)
             }
         }
+    }
 
-        if let OpType::Dir = create_type {
-            self.clean_cluster_data(blank_cluster);
-            self.add_dot_item(blank_cluster);
+    /// Pick Short Alias Of Long Name Like Windows: The Basis If Nothing Was Lost
+    /// And No Item Has It, Else The Basis With The Smallest Free "~N" Tail
+    fn short_alias(&self, value: &str) -> Result<[u8; 11], DirError> {
+        let basis = BasisName::new(value, self.code_page);
+        if !basis.lossy && !self.sfn_used(|sfn| sfn == basis.bytes)? {
+            return Ok(basis.bytes);
         }
-        Ok(())
+
+        // one scan marks used tails in a window of TAIL_WINDOW
+        const TAIL_WINDOW: u32 = 1024;
+        let mut start = 1;
+        while start <= MAX_TAIL {
+            let mut used = [0u32; TAIL_WINDOW as usize / 32];
+            self.sfn_used(|sfn| {
+                if let Some(tail) = parse_tail(&sfn) {
+                    if (start..start + TAIL_WINDOW).contains(&tail) && basis.with_tail(tail) == sfn {
+                        let i = (tail - start) as usize;
+                        used[i / 32] |= 1 << (i % 32);
+                    }
+                }
+                false
+            })?;
+            let free = (0..TAIL_WINDOW).map(|i| i + start)
+                .find(|&tail| tail <= MAX_TAIL && used[(tail - start) as usize / 32] & (1 << ((tail - start) % 32)) == 0);
+            if let Some(tail) = free { return Ok(basis.with_tail(tail)); }
+            start += TAIL_WINDOW;
+        }
+        Err(DirError::NoShortAlias)
     }
 
-    /// Basic Delete Function
-    fn delete(&mut self, value: &str, delete_type: OpType) -> Result<(), DirError> {
-        if is_illegal(value) { return Err(DirError::IllegalChar); }
+    /// Whether f Holds For Short Name Bytes Of Any Item In Dir
+    fn sfn_used<F>(&self, f: F) -> Result<bool, DirError>
+        where F: FnMut([u8; 11]) -> bool {
         let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+        let found = iter.by_ref()
+            .filter(|d| !d.is_deleted() && !d.is_lfn())
+            .filter_map(|d| d.sfn_bytes())
+            .any(f);
+        iter.check()?;
+        Ok(found)
+    }
 
-        match self.exist_iter(&mut iter, value) {
-            None => return match delete_type {
-                OpType::Dir => Err(DirError::NoMatchDir),
-                OpType::File => Err(DirError::NoMatchFile)
-            },
-            Some(di) => {
-                match delete_type {
-                    OpType::Dir if di.is_file() => return Err(DirError::NoMatchDir),
-                    OpType::File if di.is_dir() => return Err(DirError::NoMatchFile),
-                    OpType::Dir => self.delete_in_dir(di.cluster()),
-                    OpType::File => ()
-                }
-                self.fat.write(di.cluster(), 0);
-            }
+    /// Make File<T> From Its Item, Which Is At Position item In Dir
+    fn file(&self, di: Entry, item: usize, options: OpenOptions) -> File<'a, T> {
+        let fat = FAT::new(di.cluster(),
+                           self.device,
+                           self.bpb);
+        File::<T> {
+            device: self.device,
+            bpb: self.bpb,
+            dir_cluster: self.detail.cluster(),
+            item,
+            detail: di,
+            fat,
+            options,
+            extents: ExtentCache::new(),
+            pos: 0,
+            unlinked: None,
         }
+    }
 
-        match sfn_or_lfn(value) {
-            NameType::SFN => {
-                iter.previous();
-                iter.set_deleted();
-                iter.update();
+    /// Basic Delete Function, Dir Is Deleted With Its Contents If recursive
+    fn delete(&mut self, value: &str, delete_type: OpType, recursive: bool) -> Result<(), DirError> {
+        let value = check_name(value)?;
+        self.refresh_index()?;
+        let mut iter = DirIter::new(self.device, self.fat, self.bpb);
+
+        match self.lookup(&mut iter, value) {
+            None => {
+                iter.check()?;
+                return match delete_type {
+                    OpType::Dir => Err(DirError::NoMatchDir),
+                    OpType::File => Err(DirError::NoMatchFile)
+                };
             }
-            NameType::LFN => {
-                let count = get_count_of_lfn(value);
-                for _ in 0..=count {
-                    iter.previous();
-                    iter.set_deleted();
-                    iter.update();
+            Some((di, lfn_count)) => match delete_type {
+                OpType::Dir if di.is_file() => return Err(DirError::NoMatchDir),
+                OpType::File if di.is_dir() => return Err(DirError::IsADirectory),
+                _ => {
+                    if di.is_dir() && !recursive && !self.sub_dir(di).is_empty()? {
+                        return Err(DirError::DirNotEmpty);
+                    }
+                    // items are deleted before clusters are freed,
+                    // so a power cut can only leak the clusters
+                    self.index.remove(iter.item_position() - lfn_count - 1);
+                    Self::delete_items(&mut iter, lfn_count)?;
+                    if di.is_dir() && recursive { self.sub_dir(di).free_deep_tree()?; }
+                    let result = self.fat.free(di.cluster());
+                    let info = self.fat.update_fs_info();
+                    result.and(info)?;
                 }
             }
         }
         Ok(())
     }
 
-    /// Delete ALL File And Dir Which Included Deleted Dir
-    fn delete_in_dir(&self, cluster: u32) {
-        let fat_offset = self.bpb.fat1();
-        let fat = FAT::new(cluster, self.device, fat_offset);
-        let mut iter = DirIter::new(self.device, fat, self.bpb);
+    /// Mark SFN Item Before The Iterator AND Its lfn_count LFN Items Deleted
+    fn delete_items(iter: &mut DirIter<T>, lfn_count: usize) -> Result<(), FATError> {
+        for _ in 0..=lfn_count {
+            iter.previous();
+            iter.set_deleted();
+            iter.update()?;
+        }
+        Ok(())
+    }
+
+    /// Free Clusters Of ALL File And Dir In Dir, Contents Before Their Dir.
+    /// The Tree Must Fit In DELETE_DEPTH, Broken Chains Are Freed As Far As They Go
+    fn free_tree(&mut self) -> Result<(), FATError> {
+        let mut walk = self.walk::<DELETE_DEPTH>().contents_first();
+        while let Some(item) = walk.next_entry() {
+            if let Ok((_, _, d)) = item { self.fat.free(d.entry().cluster())?; }
+        }
+        self.fat.update_fs_info()
+    }
+
+    /// Free Clusters Of ALL File And Dir In Dir At Any Depth, Dir Must Be Unlinked Already.
+    /// A Dir Too Deep For The Walk Is Unlinked AND Freed First, Then The Walk Starts Again
+    fn free_deep_tree(&mut self) -> Result<(), DirError> {
+        // every dir holds a cluster, so a deeper tree has a loop
+        let mut steps = self.bpb.cluster_count() as usize;
         loop {
-            if let Some(d) = iter.next() {
-                if d.is_dir() { self.delete_in_dir(d.cluster()); }
-                if d.is_deleted() { continue; }
-                iter.previous();
-                iter.set_deleted();
-                iter.update();
-                iter.next();
-            } else {
-                break;
+            let mut dir = self.sub_dir(self.detail);
+            let mut cut = None;
+            while let Some(deep) = dir.deepest_dir()? {
+                steps = steps.checked_sub(1).ok_or(DirError::CorruptChain)?;
+                dir = dir.sub_dir(deep.entry());
+                cut = Some(deep);
             }
+            let deep = match cut {
+                Some(deep) => deep,
+                None => {
+                    self.free_tree()?;
+                    return Ok(());
+                }
+            };
+
+            // the item goes first, so a power cut can only leak the clusters
+            let fat = FAT::new(deep.dir_cluster(), self.device, self.bpb);
+            let mut iter = DirIter::new(self.device, fat, self.bpb);
+            if !iter.seek(deep.position()) || iter.next().is_none() { return Err(DirError::CorruptChain); }
+            Self::delete_items(&mut iter, 0)?;
+            dir.free_tree()?;
+            self.fat.free(deep.entry().cluster())?;
+            self.fat.update_fs_info()?;
         }
     }
 
-    /// Write Directory Item
-    fn write_directory_item(&self, di: Entry) {
+    /// Find Dir Which Walk Of DELETE_DEPTH Can't Enter, None If The Whole Tree Fits
+    fn deepest_dir(&self) -> Result<Option<DirEntry>, DirError> {
+        let mut walk = self.walk::<DELETE_DEPTH>();
+        let mut last = None;
+        while let Some(item) = walk.next_entry() {
+            match item {
+                Ok((_, _, d)) => last = Some(d),
+                Err(DirError::TooDeep) => return Ok(last),
+                Err(_) => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether Dir Has No Item Besides "." AND "..", Deleted Ones Don't Count
+    fn is_empty(&self) -> Result<bool, DirError> {
         let mut iter = DirIter::new(self.device, self.fat, self.bpb);
-        iter.find(|_| false);
-        iter.update_item(&di.bytes());
-        iter.update();
+        let empty = !iter.any(|d| !d.is_deleted());
+        iter.check()?;
+        Ok(empty)
+    }
+
+    /// Make Dir<T> From Its Item
+    fn sub_dir(&self, di: Entry) -> Dir<'a, T> {
+        Dir {
+            device: self.device,
+            bpb: self.bpb,
+            detail: di,
+            fat: FAT::new(di.cluster(), self.device, self.bpb),
+            code_page: self.code_page,
+            index: DirIndex::new(),
+        }
+    }
+
+    /// Get Root Dir Of The Volume
+    pub(crate) fn root(&self) -> Dir<'a, T> {
+        self.sub_dir(Entry::root_dir(self.bpb.root_cluster))
     }
 
     /// Clean Sectors In Cluster, To Avoid Dirty Data
-    fn clean_cluster_data(&self, cluster: u32) {
+    fn clean_cluster_data(&self, cluster: u32) -> Result<(), FATError> {
         let spc = self.bpb.sector_per_cluster_usize();
         for i in 0..spc {
             let offset = self.bpb.offset(cluster) + i * BUFFER_SIZE;
             self.device.write(&[0; BUFFER_SIZE],
                               offset,
-                              1).unwrap();
+                              1).map_err(|_| FATError::WriteFailed)?;
         }
+        Ok(())
     }
 
     /// Add '.' AND '..' Item
-    fn add_dot_item(&self, cluster: u32) {
+    fn add_dot_item(&self, cluster: u32) -> Result<(), FATError> {
         let mut buffer = [0; BUFFER_SIZE];
 
         let mut value = [0x20; 11];
@@ -302,7 +758,7 @@ impl<'a, T> Dir<'a, T>
         buffer[32..64].copy_from_slice(&di.bytes());
 
         let offset = self.bpb.offset(cluster);
-        self.device.write(&buffer, offset, 1).unwrap();
+        self.device.write(&buffer, offset, 1).map_err(|_| FATError::WriteFailed)
     }
 }
 
@@ -317,6 +773,8 @@ pub struct DirIter<'a, T>
     offset: usize,
     sector_offset: usize,
     index: usize,
+    /// Count Of Items Before The Current One
+    position: usize,
     buffer: [u8; BUFFER_SIZE],
 }
 
@@ -324,28 +782,122 @@ impl<'a, T> DirIter<'a, T>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
     pub(crate) fn new(device: T, fat: FAT<T>, bpb: &BIOSParameterBlock)
-                      -> DirIter<T> {
+                      -> DirIter<'_, T> {
         let mut fat = fat;
-        fat.next();
+        let (offset, sector_offset) = match fat.next() {
+            Some(_) => (bpb.offset(fat.current_cluster), 0),
+            None => (0, bpb.sector_per_cluster_usize())
+        };
 
         DirIter::<T> {
             device,
             fat,
             bpb,
-            offset: bpb.offset(fat.current_cluster),
-            sector_offset: 0,
+            offset,
+            sector_offset,
             index: 0,
+            position: 0,
             buffer: [0; BUFFER_SIZE],
         }
     }
 
+    /// Move New Iterator To The Item At position, false If Dir Is Shorter.
+    /// The End Of The Last Cluster Is A Position Too, Where Dir Grows
+    pub(crate) fn seek(&mut self, position: usize) -> bool {
+        let spc = self.bpb.sector_per_cluster_usize();
+        let per_cluster = spc * BUFFER_SIZE / 32;
+        let (clusters, rest) = (position / per_cluster, position % per_cluster);
+        if self.is_end_sector() { return false; }
+
+        let steps = self.fat.advance(clusters);
+        if steps != clusters {
+            if steps + 1 != clusters || rest != 0 { return false; }
+            self.offset = self.bpb.offset(self.fat.current_cluster);
+            self.sector_offset = spc;
+            self.index = 0;
+            self.position = position;
+            return true;
+        }
+        self.offset = self.bpb.offset(self.fat.current_cluster);
+        self.sector_offset = rest * 32 / BUFFER_SIZE;
+        self.index = rest * 32 % BUFFER_SIZE;
+        self.position = position;
+        self.update_buffer();
+        true
+    }
+
+    /// Move New Iterator To The Item cursor Points At, false If It Is Not In Dir
+    pub(crate) fn seek_cursor(&mut self, cursor: &DirCursor) -> bool {
+        let spc = self.bpb.sector_per_cluster_usize();
+        let (sector, index) = (cursor.sector as usize, cursor.index as usize);
+        if self.is_end_sector() || sector >= spc || index >= BUFFER_SIZE / 32 { return false; }
+
+        let steps = match self.fat.advance_to(cursor.cluster) {
+            Some(steps) => steps,
+            None => return false
+        };
+        self.offset = self.bpb.offset(self.fat.current_cluster);
+        self.sector_offset = sector;
+        self.index = index * 32;
+        self.position = steps * spc * BUFFER_SIZE / 32 + sector * BUFFER_SIZE / 32 + index;
+        self.update_buffer();
+        true
+    }
+
+    /// Get Cursor Pointing At The Current Item
+    pub(crate) fn cursor(&self) -> DirCursor {
+        let item = &self.buffer[self.index..self.index + 32];
+        DirCursor {
+            cluster: self.fat.current_cluster,
+            sector: self.sector_offset as u16,
+            index: (self.index / 32) as u16,
+            // name AND first cluster tell the item apart from one written in its place
+            check: fnv1a(item[0..11].iter().chain(&item[0x14..0x16]).chain(&item[0x1A..0x1C]).copied()),
+        }
+    }
+
+    /// Next Item Like next() With Cursor Pointing At It
+    pub(crate) fn next_with_cursor(&mut self) -> Option<(Entry, DirCursor)> {
+        loop {
+            if self.index == 0 { self.update_buffer(); }
+
+            if self.is_end() { return None; };
+
+            if self.is_special_item() {
+                self.offset_index();
+            } else {
+                let cursor = self.cursor();
+                let di = Entry::from_buf(self.get_part_buf());
+                self.offset_index();
+                return Some((di, cursor));
+            }
+        }
+    }
+
+    /// Count Of Items Before The Current One
+    pub(crate) fn item_position(&self) -> usize {
+        self.position
+    }
+
+    /// Whether The Seeked Iterator Is At The End Of Dir, Where New Items Go
+    pub(crate) fn at_end(&self) -> bool {
+        self.is_end()
+    }
+
+    /// Err If The Cluster Chain Of Dir Is Broken, Then Iterating Stopped Early
+    pub(crate) fn check(&self) -> Result<(), DirError> {
+        Ok(self.fat.check()?)
+    }
+
     fn offset_value(&self) -> usize {
         self.offset + self.sector_offset * BUFFER_SIZE
     }
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn offset_index(&mut self) {
         let spc = self.bpb.sector_per_cluster_usize();
 
+        self.position += 1;
         self.index += 32;
         if self.index % BUFFER_SIZE == 0 {
             self.sector_offset += 1;
@@ -389,12 +941,19 @@ impl<'a, T> DirIter<'a, T>
     }
 
     pub(crate) fn update_item(&mut self, buf: &[u8]) {
-        // append cluster if is dir end
+        self.buffer[self.index..self.index + 32].copy_from_slice(buf);
+    }
+
+    pub(crate) fn update_fs_info(&mut self) -> Result<(), FATError> {
+        self.fat.update_fs_info()
+    }
+
+    /// Append Cluster To Dir If The Iterator Is At The End Of Its Last Cluster
+    fn grow(&mut self) -> Result<(), FATError> {
         if self.is_end_sector() {
-            let blank_cluster = self.fat.blank_cluster();
-            self.clean_new_cluster_data(blank_cluster);
-            self.fat.write(blank_cluster, 0x0FFFFFFF);
-            self.fat.write(self.fat.current_cluster, blank_cluster);
+            let blank_cluster = self.fat.allocate(1, Some(self.fat.current_cluster))?;
+            self.clean_new_cluster_data(blank_cluster)?;
+            self.fat.write(self.fat.current_cluster, blank_cluster)?;
             self.fat.previous();
             self.fat.next();
             self.fat.next();
@@ -403,10 +962,21 @@ impl<'a, T> DirIter<'a, T>
             self.sector_offset = 0;
             self.update_buffer();
         }
-        self.buffer[self.index..self.index + 32].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Write Item Where The Iterator Stops, Then Move To The Next Item
+    pub(crate) fn append_item(&mut self, buf: &[u8]) -> Result<(), FATError> {
+        self.grow()?;
+        self.update_item(buf);
+        self.update()?;
+        self.offset_index();
+        if self.index == 0 && !self.is_end_sector() { self.update_buffer(); }
+        Ok(())
     }
 
     pub(crate) fn previous(&mut self) {
+        self.position -= 1;
         if self.index == 0 && self.sector_offset != 0 {
             self.index = BUFFER_SIZE - 32;
             self.sector_offset -= 1;
@@ -418,6 +988,7 @@ impl<'a, T> DirIter<'a, T>
             self.sector_offset = spc - 1;
             self.index = BUFFER_SIZE - 32;
             self.fat.previous();
+            self.offset = self.bpb.offset(self.fat.current_cluster);
             self.update_buffer();
         }
     }
@@ -429,20 +1000,21 @@ impl<'a, T> DirIter<'a, T>
                          1).unwrap();
     }
 
-    pub(crate) fn update(&self) {
+    pub(crate) fn update(&self) -> Result<(), FATError> {
         self.device.write(&self.buffer,
                           self.offset_value(),
-                          1).unwrap();
+                          1).map_err(|_| FATError::WriteFailed)
     }
 
-    fn clean_new_cluster_data(&self, cluster: u32) {
+    fn clean_new_cluster_data(&self, cluster: u32) -> Result<(), FATError> {
         let spc = self.bpb.sector_per_cluster_usize();
         for i in 0..spc {
             let offset = self.bpb.offset(cluster) + i * BUFFER_SIZE;
             self.device.write(&[0; BUFFER_SIZE],
                               offset,
-                              1).unwrap();
+                              1).map_err(|_| FATError::WriteFailed)?;
         }
+        Ok(())
     }
 }
 
@@ -453,18 +1025,723 @@ impl<'a, T> Iterator for DirIter<'a, T>
     type Item = Entry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index == 0 { self.update_buffer(); }
+        self.next_with_cursor().map(|(di, _)| di)
+    }
+}
 
-        if self.is_end() { return None; };
+/// Where A Listing Of Dir Stopped, Kept Between Pages Without Borrowing Dir.
+/// Points At The Short Item Of The Last Listed Entry, Default Points At The Start
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DirCursor {
+    cluster: u32,
+    /// Sector In Cluster
+    sector: u16,
+    /// Item In Sector
+    index: u16,
+    /// Hash Of The Item, To Tell If It Changed
+    check: u32,
+}
 
-        if self.is_special_item() {
-            self.offset_index();
-            self.next()
-        } else {
-            let buf = self.get_part_buf();
-            let di = Entry::from_buf(buf);
-            self.offset_index();
-            Some(di)
+impl DirCursor {
+    /// Get Bytes To Save Cursor
+    pub fn to_bytes(&self) -> [u8; 12] {
+        let mut bytes = [0; 12];
+        bytes[0..4].copy_from_slice(&self.cluster.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.sector.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.index.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.check.to_le_bytes());
+        bytes
+    }
+
+    /// Make Cursor From Saved Bytes
+    pub fn from_bytes(bytes: &[u8; 12]) -> Self {
+        Self {
+            cluster: read_le_u32(&bytes[0..4]),
+            sector: read_le_u16(&bytes[4..6]),
+            index: read_le_u16(&bytes[6..8]),
+            check: read_le_u32(&bytes[8..12]),
+        }
+    }
+}
+
+/// Entries Of One Dir In Order, Made By Dir::entries OR Dir::entries_from
+pub struct Entries<'a, T>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    iter: DirIter<'a, T>,
+    code_page: &'static dyn OemCodePage,
+    dir_cluster: u32,
+    item: DirEntry,
+    cursor: DirCursor,
+    changed: bool,
+    done: bool,
+}
+
+impl<'a, T> Entries<'a, T>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    fn new<const N: usize>(dir: &Dir<'a, T, N>, iter: DirIter<'a, T>, cursor: DirCursor, changed: bool) -> Self {
+        Self {
+            iter,
+            code_page: dir.code_page,
+            dir_cluster: dir.detail.cluster(),
+            item: DirEntry::new(),
+            cursor,
+            changed,
+            done: false,
+        }
+    }
+
+    /// Get Cursor At The Last Entry Returned, Dir::entries_from Resumes After It.
+    /// Before The First Entry It Is The Cursor Listing Started From
+    pub fn cursor(&self) -> DirCursor {
+        self.cursor
+    }
+
+    /// Whether The Entry Listing Resumed After Was Deleted OR Replaced Since Its Cursor Was Taken.
+    /// Items Of Dir Never Move, New Ones Go At The End, So Entries After It Are Listed All The Same
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+}
+
+impl<'a, T> Iterator for Entries<'a, T>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    type Item = Result<DirEntry, DirError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None; }
+        while let Some((item, cursor)) = self.iter.next_with_cursor() {
+            if self.item.collect(item, self.dir_cluster, self.iter.item_position() - 1, self.code_page) {
+                self.cursor = cursor;
+                return Some(Ok(self.item));
+            }
+        }
+        self.done = true;
+        self.iter.check().err().map(Err)
+    }
+}
+
+/// Sizes Of A Dir Tree
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct DiskUsage {
+    pub files: usize,
+    pub dirs: usize,
+    /// Sum Of File Lengths
+    pub bytes: usize,
+    /// Bytes Of Clusters Held By Files AND Dirs
+    pub allocated: usize,
+}
+
+/// Depth, Dirs Above AND The Entry, As Walk<T> Returns Them
+pub type WalkItem<'w> = (usize, &'w [DirEntry], DirEntry);
+
+/// Depth-First Walk Of Dir Tree, No Alloc. Made By Dir::walk()
+pub struct Walk<'a, T, const N: usize>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    device: T,
+    bpb: &'a BIOSParameterBlock,
+    code_page: &'static dyn OemCodePage,
+    /// First Cluster Of The Walked Dir
+    cluster: u32,
+    /// Iterator Of Each Dir On The Way Down, The Walked Dir First
+    iters: [Option<DirIter<'a, T>>; N],
+    /// Item Of Each Dir On The Way Down Below The Walked Dir
+    path: [DirEntry; N],
+    len: usize,
+    /// Dir Returned Last, It Is Entered On The Next Call
+    pending: Option<DirEntry>,
+    contents_first: bool,
+    item: DirEntry,
+}
+
+impl<'a, T, const N: usize> Walk<'a, T, N>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    fn new<const M: usize>(dir: &Dir<'a, T, M>) -> Self {
+        let mut iters = [None; N];
+        if N != 0 { iters[0] = Some(DirIter::new(dir.device, dir.fat, dir.bpb)); }
+        Self {
+            device: dir.device,
+            bpb: dir.bpb,
+            code_page: dir.code_page,
+            cluster: dir.detail.cluster(),
+            iters,
+            path: [DirEntry::new(); N],
+            len: N.min(1),
+            pending: None,
+            contents_first: false,
+            item: DirEntry::new(),
+        }
+    }
+
+    /// Keep Entries Whose Path Below The Walked Dir Matches pattern, Like "music/**/*.wav".
+    /// Components Are Split By '/' AND Matched Like Dir::find, "**" Matches Any Count Of Dirs
+    pub fn glob<'p>(self, pattern: &'p str) -> Find<'a, 'p, T, N> {
+        Find {
+            walk: self,
+            pattern,
+            error: None,
+        }
+    }
+
+    /// Return Dirs After Their Contents, skip_subtree() Does Nothing Then
+    pub fn contents_first(mut self) -> Self {
+        self.contents_first = true;
+        self
+    }
+
+    /// Don't Enter The Dir Returned Last
+    pub fn skip_subtree(&mut self) {
+        self.pending = None;
+    }
+
+    /// Get Next Entry With Its Depth AND The Dirs Above It Below The Walked Dir.
+    /// Depth Is 0 For Entries Of The Walked Dir
+    pub fn next_entry(&mut self) -> Option<Result<WalkItem<'_>, DirError>> {
+        loop {
+            if let Some(dir) = self.pending.take() {
+                if let Err(e) = self.push(dir) { return Some(Err(e)); }
+            }
+            if self.len == 0 { return None; }
+
+            let depth = self.len - 1;
+            let dir_cluster = if depth == 0 { self.cluster } else { self.path[depth - 1].entry().cluster() };
+            let iter = self.iters[depth].as_mut().unwrap();
+            let found = loop {
+                match iter.next() {
+                    Some(item) => if self.item.collect(item, dir_cluster, iter.item_position() - 1, self.code_page) { break true; },
+                    None => break false,
+                }
+            };
+
+            if found {
+                let entry = self.item;
+                if entry.is_dir() {
+                    if !self.contents_first {
+                        self.pending = Some(entry);
+                    } else {
+                        if let Err(e) = self.push(entry) { return Some(Err(e)); }
+                        continue;
+                    }
+                }
+                return Some(Ok((depth, &self.path[0..depth], entry)));
+            }
+
+            let checked = iter.check();
+            self.iters[depth] = None;
+            self.len -= 1;
+            if let Err(e) = checked { return Some(Err(e)); }
+            if self.contents_first && depth != 0 {
+                return Some(Ok((depth - 1, &self.path[0..depth - 1], self.path[depth - 1])));
+            }
+        }
+    }
+
+    /// Enter dir, Err If The Stack Is Full
+    fn push(&mut self, dir: DirEntry) -> Result<(), DirError> {
+        if self.len == N { return Err(DirError::TooDeep); }
+        let fat = FAT::new(dir.entry().cluster(), self.device, self.bpb);
+        self.iters[self.len] = Some(DirIter::new(self.device, fat, self.bpb));
+        self.path[self.len - 1] = dir;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// Entries Of A Walk Whose Path Matches A Pattern. Made By Dir::find OR Walk::glob
+pub struct Find<'a, 'p, T, const N: usize>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    walk: Walk<'a, T, N>,
+    pattern: &'p str,
+    /// Given Once Instead Of Any Entry
+    error: Option<DirError>,
+}
+
+impl<'a, 'p, T, const N: usize> Iterator for Find<'a, 'p, T, N>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    type Item = Result<DirEntry, DirError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.walk.len = 0;
+            return Some(Err(e));
+        }
+
+        // without "**" no match is deeper than the count of components
+        let max_depth = match self.pattern.split('/').any(|part| part == "**") {
+            true => None,
+            false => Some(self.pattern.split('/').count() - 1),
+        };
+
+        loop {
+            let (depth, path, entry) = match self.walk.next_entry()? {
+                Ok(item) => item,
+                Err(e) => return Some(Err(e)),
+            };
+            let matched = path_match(self.pattern, path, &entry, 0);
+            if let Some(max) = max_depth {
+                // a dir whose path leaves the pattern holds no match
+                let mut parts = self.pattern.split('/');
+                let on_the_way = path.iter().chain(Some(&entry))
+                    .all(|d| parts.next().is_some_and(|part| wildcard_match(part, d.name_utf16())));
+                if depth >= max || !on_the_way { self.walk.skip_subtree(); }
+            }
+            if matched { return Some(Ok(entry)); }
+        }
+    }
+}
+
+/// Match Names Of path From i On, Then entry, Against pattern Split By '/'
+fn path_match(pattern: &str, path: &[DirEntry], entry: &DirEntry, i: usize) -> bool {
+    let name = |i: usize| if i < path.len() { path[i].name_utf16() } else { entry.name_utf16() };
+    let total = path.len() + 1;
+    let (head, rest) = match pattern.find('/') {
+        Some(at) => (&pattern[0..at], Some(&pattern[at + 1..])),
+        None => (pattern, None),
+    };
+
+    match (head, rest) {
+        ("**", None) => i < total,
+        ("**", Some(rest)) => (i..total).any(|j| path_match(rest, path, entry, j)),
+        _ if i == total || !wildcard_match(head, name(i)) => false,
+        (_, None) => i + 1 == total,
+        (_, Some(rest)) => path_match(rest, path, entry, i + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+    use crate::ram::{Ram, power_cuts};
+    use crate::volume::Volume;
+
+    fn file_name(i: usize) -> String {
+        std::format!("Long File Name {}.txt", i)
+    }
+
+    /// Names Of The Rest Of A Listing
+    fn names<T>(entries: Entries<'_, T>) -> Vec<String>
+        where T: BlockDevice + Clone + Copy,
+              <T as BlockDevice>::Error: core::fmt::Debug {
+        entries.map(|entry| entry.unwrap().name().collect()).collect()
+    }
+
+    /// Volume With 10 Long Names In Root Dir, Over More Than One Cluster
+    fn listed() -> Ram {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        for i in 0..10 { root.create_file(&file_name(i)).unwrap(); }
+        ram
+    }
+
+    /// Cursor At The Entry Of file_name(3), Saved As Bytes
+    fn page(ram: Ram) -> [u8; 12] {
+        let volume = Volume::new(ram);
+        let mut entries = volume.root_dir().entries();
+        let page: Vec<String> = entries.by_ref().take(4).map(|entry| entry.unwrap().name().collect()).collect();
+        assert_eq!(page, (0..4).map(file_name).collect::<Vec<_>>());
+        entries.cursor().to_bytes()
+    }
+
+    #[test]
+    fn cursor_bytes() {
+        assert_eq!(DirCursor::from_bytes(&[0; 12]), DirCursor::default());
+        let cursor = DirCursor { cluster: 0x0102_0304, sector: 5, index: 15, check: 0xDEAD_BEEF };
+        assert_eq!(DirCursor::from_bytes(&cursor.to_bytes()), cursor);
+
+        let ram = listed();
+        let saved = page(ram);
+        let volume = Volume::new(ram);
+        let entries = volume.root_dir().entries_from(&DirCursor::from_bytes(&saved)).unwrap();
+        assert!(!entries.is_changed());
+        assert_eq!(entries.cursor().to_bytes(), saved);
+        assert_eq!(names(entries), (4..10).map(file_name).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn resume_after_insert() {
+        let ram = listed();
+        let saved = page(ram);
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file("new.txt").unwrap();
+
+        let entries = root.entries_from(&DirCursor::from_bytes(&saved)).unwrap();
+        assert!(!entries.is_changed());
+        let mut expected: Vec<String> = (4..10).map(file_name).collect();
+        expected.push(String::from("new.txt"));
+        assert_eq!(names(entries), expected);
+    }
+
+    #[test]
+    fn resume_after_delete() {
+        // an entry before the cursor is gone
+        let ram = listed();
+        let saved = page(ram);
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.delete_file(&file_name(1)).unwrap();
+        root.delete_file(&file_name(5)).unwrap();
+
+        let entries = root.entries_from(&DirCursor::from_bytes(&saved)).unwrap();
+        assert!(!entries.is_changed());
+        assert_eq!(names(entries), [4, 6, 7, 8, 9].iter().map(|&i| file_name(i)).collect::<Vec<_>>());
+
+        // the entry at the cursor is gone
+        let ram = listed();
+        let saved = page(ram);
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.delete_file(&file_name(3)).unwrap();
+
+        let entries = root.entries_from(&DirCursor::from_bytes(&saved)).unwrap();
+        assert!(entries.is_changed());
+        assert_eq!(names(entries), (4..10).map(file_name).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cursor_of_other_dir() {
+        let ram = listed();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_dir("logs").unwrap();
+        let mut logs = root.cd("logs").unwrap();
+        logs.create_file("a.log").unwrap();
+        let mut entries = logs.entries();
+        entries.next().unwrap().unwrap();
+
+        assert!(matches!(root.entries_from(&entries.cursor()), Err(DirError::InvalidCursor)));
+    }
+
+    #[test]
+    fn create_power_cut() {
+        power_cuts(listed, |ram| Volume::new(ram).root_dir().create_dir("A Long Dir Name"), |ram, done| {
+            let volume = Volume::new(ram);
+            let root = volume.root_dir();
+            match root.cd("A Long Dir Name") {
+                Ok(dir) => assert_eq!(dir.entries().count(), 0),
+                Err(e) => assert!(!done && e == DirError::NoMatchDir),
+            }
+            assert_eq!(root.entries().count(), if root.exist("A Long Dir Name").is_some() { 11 } else { 10 });
+        });
+    }
+
+    #[test]
+    fn delete_power_cut() {
+        let setup = || {
+            let ram = listed();
+            let volume = Volume::new(ram);
+            let mut file = volume.root_dir().open_file(&file_name(3)).unwrap();
+            file.write(&[0x55; 3000], WriteType::Append).unwrap();
+            ram
+        };
+        power_cuts(setup, |ram| Volume::new(ram).root_dir().delete_file(&file_name(3)), |ram, done| {
+            let volume = Volume::new(ram);
+            let root = volume.root_dir();
+            let names = names(root.entries());
+            assert!(!done || names.len() == 9);
+            if names.len() == 10 {
+                let mut buf = [0; 3000];
+                assert_eq!(root.open_file(&file_name(3)).unwrap().read(&mut buf).unwrap(), 3000);
+                assert!(buf.iter().all(|&b| b == 0x55));
+            }
+        });
+    }
+
+    /// Volume With OLD In "a.txt", Over More Than One Cluster
+    fn with_old() -> Ram {
+        let ram = listed();
+        Volume::new(ram).root_dir().replace_file("a.txt", &[0x11; 1300]).unwrap();
+        ram
+    }
+
+    fn content(ram: Ram, file: &str) -> Vec<u8> {
+        let volume = Volume::new(ram);
+        let file = volume.root_dir().open_file(file).unwrap();
+        let mut buf = std::vec![0; file.length()];
+        file.read(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn replace_power_cut() {
+        power_cuts(with_old, |ram| Volume::new(ram).root_dir().replace_file("a.txt", &[0x22; 2000]), |ram, done| {
+            let content = content(ram, "a.txt");
+            if done { assert_eq!(content, [0x22; 2000]); }
+            assert!(content == [0x11; 1300] || content == [0x22; 2000]);
+            // Volume::new has deleted what was left of the temp file
+            assert!(Volume::new(ram).root_dir().exist(REPLACE_TEMP).is_none());
+        });
+    }
+
+    #[test]
+    fn temp_file_is_deleted_when_mounted() {
+        let ram = with_old();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_hidden_file(REPLACE_TEMP).unwrap();
+        root.open_file(REPLACE_TEMP).unwrap().write(&[0x33; 3000], WriteType::Append).unwrap();
+
+        let volume = Volume::new(ram);
+        assert!(volume.root_dir().exist(REPLACE_TEMP).is_none());
+        let report = volume.fsck::<4>(&mut [0; 256]).unwrap();
+        assert_eq!((report.broken, report.crosslinked, report.leaked), (0, 0, 0));
+        assert_eq!(content(ram, "a.txt"), [0x11; 1300]);
+    }
+
+    #[test]
+    fn user_file_named_like_temp_file_is_kept() {
+        let ram = with_old();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file(REPLACE_TEMP).unwrap();
+        root.open_file(REPLACE_TEMP).unwrap().write(b"mine", WriteType::Append).unwrap();
+
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        assert_eq!(root.replace_file("a.txt", &[0x22; 10]), Err(DirError::AlreadyExists));
+        assert_eq!(content(ram, REPLACE_TEMP), b"mine");
+        assert_eq!(content(ram, "a.txt"), [0x11; 1300]);
+    }
+
+    /// Volume With "y.wav", "z.txt" AND "music/a/x.wav"
+    fn music() -> Ram {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file("y.wav").unwrap();
+        root.create_file("z.txt").unwrap();
+        root.create_dir("music").unwrap();
+        let mut music = root.cd("music").unwrap();
+        music.create_dir("a").unwrap();
+        music.cd("a").unwrap().create_file("x.wav").unwrap();
+        ram
+    }
+
+    fn found<I: Iterator<Item = Result<DirEntry, DirError>>>(find: I) -> Vec<String> {
+        find.map(|entry| entry.unwrap().name().collect()).collect()
+    }
+
+    #[test]
+    fn find_in_dir_only() {
+        let volume = Volume::new(music());
+        let root = volume.root_dir();
+        assert_eq!(found(root.find("*.wav")), ["y.wav"]);
+        assert_eq!(found(root.find("*")), ["y.wav", "z.txt", "music"]);
+    }
+
+    #[test]
+    fn find_rejects_path_patterns() {
+        let volume = Volume::new(music());
+        let root = volume.root_dir();
+        for pattern in ["**/*.wav", "music/*", "**"].iter() {
+            let mut find = root.find(pattern);
+            assert_eq!(find.next().map(|entry| entry.err()), Some(Some(DirError::PathPattern)));
+            assert!(find.next().is_none());
+        }
+        assert_eq!(found(root.walk::<3>().glob("**/*.wav")), ["y.wav", "x.wav"]);
+        assert_eq!(found(root.walk::<3>().glob("music/*/*.wav")), ["x.wav"]);
+    }
+
+    /// Depth AND Path Of Each Entry, Names Joined By '/'
+    fn walked<const N: usize>(mut walk: Walk<'_, Ram, N>) -> Vec<Result<(usize, String), DirError>> {
+        let mut items = Vec::new();
+        while let Some(item) = walk.next_entry() {
+            items.push(item.map(|(depth, path, entry)| {
+                let mut name = String::new();
+                for dir in path.iter().chain(Some(&entry)) {
+                    if !name.is_empty() { name.push('/'); }
+                    name.extend(dir.name());
+                }
+                (depth, name)
+            }));
         }
+        items
+    }
+
+    fn ok(items: &[(usize, &str)]) -> Vec<Result<(usize, String), DirError>> {
+        items.iter().map(|&(depth, name)| Ok((depth, String::from(name)))).collect()
+    }
+
+    #[test]
+    fn walk_order() {
+        let volume = Volume::new(music());
+        let root = volume.root_dir();
+        assert_eq!(walked(root.walk::<3>()), ok(&[
+            (0, "y.wav"), (0, "z.txt"), (0, "music"), (1, "music/a"), (2, "music/a/x.wav"),
+        ]));
+        assert_eq!(walked(root.walk::<3>().contents_first()), ok(&[
+            (0, "y.wav"), (0, "z.txt"), (2, "music/a/x.wav"), (1, "music/a"), (0, "music"),
+        ]));
+
+        let mut walk = root.walk::<3>();
+        let mut names = Vec::new();
+        while let Some(item) = walk.next_entry() {
+            let (_, _, entry) = item.unwrap();
+            if entry.is_dir() { walk.skip_subtree(); }
+            names.push(entry.name().collect::<String>());
+        }
+        assert_eq!(names, ["y.wav", "z.txt", "music"]);
+    }
+
+    #[test]
+    fn walk_too_deep() {
+        let volume = Volume::new(music());
+        let mut items = ok(&[(0, "y.wav"), (0, "z.txt"), (0, "music"), (1, "music/a")]);
+        items.push(Err(DirError::TooDeep));
+        assert_eq!(walked(volume.root_dir().walk::<2>()), items);
+    }
+
+    #[test]
+    fn disk_usage() {
+        let ram = music();
+        let volume = Volume::new(ram);
+        let root = volume.root_dir();
+        root.cd("music").unwrap().cd("a").unwrap().open_file("x.wav").unwrap()
+            .write(&[0x66; 1000], WriteType::Append).unwrap();
+
+        // every file AND dir holds one cluster of 512 bytes, x.wav two
+        let usage = root.du::<3>().unwrap();
+        assert_eq!(usage, DiskUsage { files: 3, dirs: 2, bytes: 1000, allocated: 6 * 512 });
+        assert_eq!(root.cd("music").unwrap().du::<2>().unwrap().allocated, 3 * 512);
+        assert_eq!(root.du::<2>(), Err(DirError::TooDeep));
+    }
+
+    #[test]
+    fn remove_dir_only_when_empty() {
+        let volume = Volume::new(music());
+        let mut root = volume.root_dir();
+        assert_eq!(root.remove_dir("music"), Err(DirError::DirNotEmpty));
+        assert!(root.cd("music").unwrap().exist("a").is_some());
+
+        let mut music = root.cd("music").unwrap();
+        assert_eq!(music.remove_dir("a"), Err(DirError::DirNotEmpty));
+        music.cd("a").unwrap().delete_file("x.wav").unwrap();
+        music.remove_dir("a").unwrap();
+        root.remove_dir("music").unwrap();
+        assert!(root.exist("music").is_none());
+    }
+
+    #[test]
+    fn remove_dir_all_frees_every_cluster() {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        let free = ram.free_clusters();
+
+        root.create_dir("logs").unwrap();
+        let mut logs = root.cd("logs").unwrap();
+        for i in 0..3 {
+            // 20 long names need more than one cluster of dir
+            logs.create_dir(&file_name(i)).unwrap();
+            let mut day = logs.cd(&file_name(i)).unwrap();
+            for j in 0..20 { day.create_file(&file_name(j)).unwrap(); }
+            day.open_file(&file_name(0)).unwrap().write(&[0x88; 3000], WriteType::Append).unwrap();
+            day.create_dir("deeper").unwrap();
+            day.cd("deeper").unwrap().create_file("last.txt").unwrap();
+        }
+        // each day holds at least its dir, 20 files, 5 more clusters of content AND the deeper dir
+        assert!(ram.free_clusters() <= free - 1 - 3 * 27);
+
+        root.remove_dir_all("logs").unwrap();
+        assert!(root.exist("logs").is_none());
+        assert_eq!(ram.free_clusters(), free);
+        let report = volume.fsck::<4>(&mut [0; 256]).unwrap();
+        assert_eq!(report, Default::default());
+    }
+
+    #[test]
+    fn one_error_per_condition() {
+        let volume = Volume::new(music());
+        let mut root = volume.root_dir();
+        assert_eq!(root.create_file("y.wav"), Err(DirError::AlreadyExists));
+        assert_eq!(root.create_dir("y.wav"), Err(DirError::AlreadyExists));
+        assert_eq!(root.create_file("music"), Err(DirError::AlreadyExists));
+        assert_eq!(root.open("y.wav", OpenOptions::new().write(true).create_new(true)).err(), Some(DirError::AlreadyExists));
+
+        assert_eq!(root.open_file("none.wav").err(), Some(DirError::NoMatchFile));
+        assert_eq!(root.open("none.wav", OpenOptions::new().read(true)).err(), Some(DirError::NoMatchFile));
+        assert_eq!(root.delete_file("none.wav"), Err(DirError::NoMatchFile));
+        assert_eq!(root.cd("none").err(), Some(DirError::NoMatchDir));
+        assert_eq!(root.cd("y.wav").err(), Some(DirError::NoMatchDir));
+        assert_eq!(root.remove_dir("y.wav"), Err(DirError::NoMatchDir));
+
+        assert_eq!(root.open_file("music").err(), Some(DirError::IsADirectory));
+        assert_eq!(root.open("music", OpenOptions::new().read(true)).err(), Some(DirError::IsADirectory));
+        assert_eq!(root.delete_file("music"), Err(DirError::IsADirectory));
+        assert_eq!(root.replace_file("music", b"data"), Err(DirError::IsADirectory));
+        let entry = root.find("music").next().unwrap().unwrap();
+        assert_eq!(root.open_entry(&entry).err(), Some(DirError::IsADirectory));
+        assert!(root.cd("music").unwrap().exist("a").is_some());
+    }
+
+    #[test]
+    fn looped_dir_ends() {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_dir("logs").unwrap();
+        let mut logs = root.cd("logs").unwrap();
+        for i in 0..20 { logs.create_file(&file_name(i)).unwrap(); }
+
+        // the first cluster of logs is full AND links to itself, so no end of dir is found
+        let first = root.exist("logs").unwrap().cluster();
+        FAT::new(first, ram, root.bpb).write(first, first).unwrap();
+
+        let logs = root.cd("logs").unwrap();
+        let last = logs.entries().last().unwrap();
+        assert_eq!(last.err(), Some(DirError::CorruptChain));
+        assert!(logs.exist("none.txt").is_none());
+        assert_eq!(root.du::<2>().err(), Some(DirError::CorruptChain));
+    }
+
+    #[test]
+    fn surrogate_pairs_round_trip() {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        let name = "日志 \u{1F600} \u{20000}.txt";
+        root.create_file(name).unwrap();
+        root.open_file(name).unwrap().write(b"smile", WriteType::Append).unwrap();
+
+        let volume = Volume::new(ram);
+        let root = volume.root_dir();
+        assert_eq!(names(root.entries()), [name]);
+        let entry = root.entries().next().unwrap().unwrap();
+        assert_eq!(entry.name_utf16().len(), name.encode_utf16().count());
+        assert!(entry.name_eq("日志 \u{1F600} \u{20000}.TXT"));
+        let file = root.open_file(name).unwrap();
+        let mut buf = [0; 5];
+        assert_eq!((file.read(&mut buf).unwrap(), &buf), (5, b"smile"));
+        assert!(root.open_file("日志 \u{1F601} \u{20000}.txt").is_err());
+    }
+
+    #[test]
+    fn long_names_are_counted_in_utf16_units() {
+        let volume = Volume::new(Ram::format());
+        let mut root = volume.root_dir();
+
+        // 13 units fill one long item, 14 need two, though both are 7 chars
+        let one = "\u{1F600}".repeat(6) + "a";
+        let two = "\u{1F600}".repeat(7);
+        root.create_file(&one).unwrap();
+        root.create_file(&two).unwrap();
+        let positions: Vec<usize> = root.entries().map(|entry| entry.unwrap().position()).collect();
+        assert_eq!(positions, [1, 4]);
+
+        // 255 units at most, each emoji takes 2
+        let longest = "\u{1F600}".repeat(125) + "a.txt";
+        root.create_file(&longest).unwrap();
+        assert_eq!(names(root.entries()).last().unwrap(), &longest);
+        let too_long = "\u{1F600}".repeat(126) + ".txt";
+        assert_eq!(root.create_file(&too_long), Err(DirError::NameTooLong));
     }
 }
@@ -1,14 +1,18 @@
 use core::str;
-use crate::tool::read_le_u32;
+use crate::tool::{read_le_u32, MAX_NAME_UNITS};
 use crate::dir::OpType;
+use crate::upcase::eq_units_ignore_case;
+use crate::codepage::OemCodePage;
 
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum NameType {
     SFN,
     LFN,
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, PartialOrd, PartialEq)]
 pub enum EntryType {
+    #[default]
     Dir,
     File,
     LFN,
@@ -34,16 +38,13 @@ impl EntryType {
     }
 }
 
-impl Default for EntryType {
-    fn default() -> Self {
-        EntryType::Dir
-    }
-}
-
 #[derive(Default, Copy, Clone, Debug)]
 pub struct ShortDirectoryEntry {
     name: [u8; 8],
     extension: [u8; 3],
+    attribute: u8,
+    /// NT Flags Of Byte 0x0C, 0x08 For Lowercase Name, 0x10 For Lowercase Extension
+    case: u8,
     length: u32,
     cluster: u32,
 }
@@ -58,10 +59,12 @@ impl ShortDirectoryEntry {
         let mut item = [0; 32];
         let _item = [0x20; 11];
         item[0x00..0x0B].copy_from_slice(&_item);
-        item[0x00..0x00 + name.len()].copy_from_slice(name.as_bytes());
+        item[0x00..name.len()].copy_from_slice(name.as_bytes());
         item[0x08..0x08 + extension.len()].copy_from_slice(extension.as_bytes());
-        item[0x00..0x00 + name.len()].make_ascii_uppercase();
+        item[0x00..name.len()].make_ascii_uppercase();
         item[0x08..0x08 + extension.len()].make_ascii_uppercase();
+        if name.contains(|ch: char| ch.is_ascii_lowercase()) { item[0x0C] |= 0x08; }
+        if extension.contains(|ch: char| ch.is_ascii_lowercase()) { item[0x0C] |= 0x10; }
 
         let mut cluster: [u8; 4] = cluster.to_be_bytes();
         cluster.reverse();
@@ -108,10 +111,14 @@ impl ShortDirectoryEntry {
 
         name.copy_from_slice(&buf[0x00..0x08]);
         extension.copy_from_slice(&buf[0x08..0x0B]);
+        // 0xE5 marks deleted item, so a name starting with it is kept as 0x05
+        if name[0] == 0x05 { name[0] = 0xE5; }
 
         Self {
             name,
             extension,
+            attribute: buf[0x0B],
+            case: buf[0x0C],
             cluster: ((buf[0x15] as u32) << 24)
                 | ((buf[0x14] as u32) << 16)
                 | ((buf[0x1B] as u32) << 8)
@@ -120,30 +127,32 @@ impl ShortDirectoryEntry {
         }
     }
 
-    fn get_full_name_bytes(&self) -> ([u8; 12], usize) {
-        let mut len = 0;
-        let mut full_name = [0; 12];
-
-        for &i in self.name.iter() {
-            if i != 0x20 {
-                full_name[len] = i;
-                len += 1;
+    /// Decode Name With Code Page, Return UTF-16 Units Of "NAME.EXT".
+    /// Name AND Extension Are Lowercase If NT Flags Say So
+    fn decode_name(&self, code_page: &dyn OemCodePage) -> ([u16; 12], usize) {
+        let (mut units, mut len) = ([0; 12], 0);
+        let mut push = |part: &[u8], lowercase: bool| {
+            let end = part.iter().rposition(|&b| b != 0x20).map_or(0, |i| i + 1);
+            let mut i = 0;
+            while i < end {
+                let (ch, n) = code_page.decode(&part[i..end]);
+                let ch = if lowercase { ch.to_ascii_lowercase() } else { ch };
+                for unit in ch.encode_utf16(&mut [0; 2]) {
+                    if len < units.len() {
+                        units[len] = *unit;
+                        len += 1;
+                    }
+                }
+                i += n;
             }
-        }
+        };
 
+        push(&self.name, self.case & 0x08 != 0);
         if self.extension[0] != 0x20 {
-            full_name[len] = b'.';
-            len += 1;
+            push(b".", false);
+            push(&self.extension, self.case & 0x10 != 0);
         }
-
-        for &i in self.extension.iter() {
-            if i != 0x20 {
-                full_name[len] = i;
-                len += 1;
-            }
-        }
-
-        (full_name, len)
+        (units, len)
     }
 
     fn bytes(&self, item_type: EntryType) -> [u8; 32] {
@@ -151,21 +160,24 @@ impl ShortDirectoryEntry {
 
         item[0x00..0x08].copy_from_slice(&self.name);
         item[0x08..0x0B].copy_from_slice(&self.extension);
+        if item[0x00] == 0xE5 { item[0x00] = 0x05; }
 
         let mut cluster: [u8; 4] = self.cluster.to_be_bytes();
         cluster.reverse();
 
         item[0x14..0x16].copy_from_slice(&cluster[2..4]);
         item[0x1A..0x1C].copy_from_slice(&cluster[0..2]);
-        item[0x0C] = 0x18;
+        item[0x0C] = self.case & 0x18;
 
         let mut length: [u8; 4] = self.length.to_be_bytes();
         length.reverse();
         item[0x1C..0x20].copy_from_slice(&length);
 
+        // read-only, hidden AND system are kept
+        let kept = self.attribute & 0x07;
         match item_type {
-            EntryType::Dir => item[0x0B] = 0x10,
-            EntryType::File => item[0x0B] = 0x20,
+            EntryType::Dir => item[0x0B] = 0x10 | kept,
+            EntryType::File => item[0x0B] = 0x20 | kept,
             EntryType::LFN => item[0x0B] = 0x0F,
             EntryType::Deleted => item[0x00] = 0xE5
         }
@@ -192,15 +204,15 @@ impl LongDirectoryEntry {
         LongDirectoryEntry::from_buf(&buf)
     }
 
+    /// Write The 13 UTF-16 Units Of value Which Entry With attribute Holds.
+    /// A Surrogate Pair Can Be Split Between Two Entries, Like Windows Does
     fn write_unicode(value: &str, buf: &mut [u8]) {
+        let ord = (buf[0x00] & 0x1F) as usize;
         let mut temp = [0xFF; 26];
         let mut index = 0;
 
-        for i in value.encode_utf16() {
-            let part1 = (i & 0xFF) as u8;
-            let part2 = ((i & 0xFF00) >> 8) as u8;
-            temp[index] = part1;
-            temp[index + 1] = part2;
+        for i in value.encode_utf16().skip((ord - 1) * 13).take(13) {
+            temp[index..index + 2].copy_from_slice(&i.to_le_bytes());
             index += 2;
         }
 
@@ -243,42 +255,18 @@ impl LongDirectoryEntry {
         }
     }
 
-    fn to_utf8(&self) -> ([u8; 13 * 3], usize) {
-        let (mut utf8, mut len) = ([0; 13 * 3], 0);
-
-        let mut op = |part: &[u8]| {
-            for i in (0..part.len()).step_by(2) {
-                if (part[i] == 0x00 && part[i + 1] == 0x00) || part[i] == 0xFF { break; }
-                let unicode = ((part[i + 1] as u16) << 8) | part[i] as u16;
-
-                if unicode <= 0x007F {
-                    utf8[len] = unicode as u8;
-                    len += 1;
-                } else if unicode >= 0x0080 && unicode <= 0x07FF {
-                    let part1 = (0b11000000 | (0b00011111 & (unicode >> 6))) as u8;
-                    let part2 = (0b10000000 | (0b00111111) & unicode) as u8;
-
-                    utf8[len] = part1;
-                    utf8[len + 1] = part2;
-                    len += 2;
-                } else if unicode >= 0x0800 {
-                    let part1 = (0b11100000 | (0b00011111 & (unicode >> 12))) as u8;
-                    let part2 = (0b10000000 | (0b00111111) & (unicode >> 6)) as u8;
-                    let part3 = (0b10000000 | (0b00111111) & unicode) as u8;
-
-                    utf8[len] = part1;
-                    utf8[len + 1] = part2;
-                    utf8[len + 2] = part3;
-                    len += 3;
-                }
-            }
-        };
-
-        op(&self.unicode_part1);
-        op(&self.unicode_part2);
-        op(&self.unicode_part3);
+    /// Get UTF-16 Units Of Name Part, Until 0x0000 OR Padding
+    fn to_utf16(self) -> ([u16; 13], usize) {
+        let (mut units, mut len) = ([0; 13], 0);
+        let parts = [&self.unicode_part1[..], &self.unicode_part2[..], &self.unicode_part3[..]];
 
-        (utf8, len)
+        for unit in parts.iter().flat_map(|part| part.chunks(2)) {
+            let unit = u16::from_le_bytes([unit[0], unit[1]]);
+            if unit == 0x0000 || unit == 0xFFFF { break; }
+            units[len] = unit;
+            len += 1;
+        }
+        (units, len)
     }
 
     fn count_of_name(&self) -> usize {
@@ -314,51 +302,40 @@ impl Entry {
         self.sfn.unwrap().cluster
     }
 
-    fn get_sfn(&self) -> Option<([u8; 12], usize)> {
-        if self.sfn.is_some() {
-            Some(self.sfn.as_ref().unwrap().get_full_name_bytes())
-        } else {
-            None
-        }
+    /// Get Name AND Extension Bytes Of Short Name, 0x05 Escape Is Undone
+    pub(crate) fn sfn_bytes(&self) -> Option<[u8; 11]> {
+        self.sfn.as_ref().map(|sfn| {
+            let mut bytes = [0; 11];
+            bytes[0..8].copy_from_slice(&sfn.name);
+            bytes[8..11].copy_from_slice(&sfn.extension);
+            bytes
+        })
     }
 
-    fn get_lfn(&self) -> Option<([u8; 13 * 3], usize)> {
-        if self.lfn.is_some() {
-            Some(self.lfn.as_ref().unwrap().to_utf8())
-        } else {
-            None
-        }
+    pub(crate) fn get_sfn(&self, code_page: &dyn OemCodePage) -> Option<([u16; 12], usize)> {
+        self.sfn.as_ref().map(|sfn| sfn.decode_name(code_page))
+    }
+
+    fn get_lfn(&self) -> Option<([u16; 13], usize)> {
+        self.lfn.map(|lfn| lfn.to_utf16())
     }
 
     pub(crate) fn count_of_name(&self) -> Option<usize> {
-        if self.lfn.is_some() {
-            Some(self.lfn.as_ref().unwrap().count_of_name())
-        } else {
-            None
-        }
+        self.lfn.as_ref().map(|lfn| lfn.count_of_name())
     }
 
     pub(crate) fn is_name_end(&self) -> Option<bool> {
-        if self.lfn.is_some() {
-            Some(self.lfn.as_ref().unwrap().is_name_end())
-        } else {
-            None
-        }
+        self.lfn.as_ref().map(|lfn| lfn.is_name_end())
     }
 
     pub(crate) fn length(&self) -> Option<usize> {
-        if self.sfn.is_some() {
-            Some(self.sfn.as_ref().unwrap().length as usize)
-        } else {
-            None
-        }
+        self.sfn.as_ref().map(|sfn| sfn.length as usize)
     }
 
     pub(crate) fn bytes(&self) -> [u8; 32] {
-        if self.sfn.is_some() {
-            self.sfn.as_ref().unwrap().bytes(self.item_type)
-        } else {
-            self.lfn.as_ref().unwrap().bytes()
+        match self.sfn.as_ref() {
+            Some(sfn) => sfn.bytes(self.item_type),
+            None => self.lfn.as_ref().unwrap().bytes()
         }
     }
 
@@ -418,28 +395,34 @@ impl Entry {
         }
     }
 
-    pub(crate) fn sfn_equal(&self, value: &str) -> bool {
+    /// Check If Short Name Decoded With Code Page Is value
+    pub(crate) fn sfn_equal(&self, value: &str, code_page: &dyn OemCodePage) -> bool {
         if self.is_deleted() { return false; }
-        let option = self.get_sfn();
+        let option = self.get_sfn(code_page);
         if option.is_none() { return false; }
-        let (bytes, len) = option.unwrap();
-        if let Ok(res) = str::from_utf8(&bytes[0..len]) {
-            value.eq_ignore_ascii_case(res)
-        } else {
-            false
-        }
+        let (units, len) = option.unwrap();
+        eq_units_ignore_case(units[0..len].iter().copied(), value.encode_utf16())
     }
 
+    /// Check If Entry Holds Its Part Of Long Name value, The Part Is Chosen By Its Order
     pub(crate) fn lfn_equal(&self, value: &str) -> bool {
         if self.is_deleted() { return false; }
         let option = self.get_lfn();
         if option.is_none() { return false; }
-        let (bytes, len) = option.unwrap();
-        if let Ok(res) = str::from_utf8(&bytes[0..len]) {
-            value.eq_ignore_ascii_case(res)
-        } else {
-            false
-        }
+        let (units, len) = option.unwrap();
+        let ord = self.count_of_name().unwrap();
+        if ord == 0 { return false; }
+        eq_units_ignore_case(units[0..len].iter().copied(),
+                             value.encode_utf16().skip((ord - 1) * 13).take(13))
+    }
+
+    pub(crate) fn set_cluster(&mut self, cluster: u32) {
+        self.sfn.as_mut().unwrap().cluster = cluster;
+    }
+
+    /// Add Attribute Bits, Only Read-Only, Hidden AND System Are Written
+    pub(crate) fn set_attribute(&mut self, attribute: u8) {
+        self.sfn.as_mut().unwrap().attribute |= attribute;
     }
 
     pub(crate) fn set_file_length(&mut self, length: usize) {
@@ -461,4 +444,137 @@ impl Entry {
     pub(crate) fn is_file(&self) -> bool {
         EntryType::File == self.item_type
     }
+
+    /// Volume Label Item In Root Dir, It Is Not A File
+    pub(crate) fn is_volume_label(&self) -> bool {
+        self.is_file() && self.sfn.is_some_and(|sfn| sfn.attribute & 0x08 != 0)
+    }
+
+    /// File With Hidden AND System Attributes, Like The Ones This Crate Keeps In Root Dir
+    pub(crate) fn is_hidden_system(&self) -> bool {
+        self.is_file() && self.sfn.is_some_and(|sfn| sfn.attribute & 0x06 == 0x06)
+    }
+}
+
+/// Item Of Dir With Its Full Name, As Listings Show It
+#[derive(Copy, Clone, Debug)]
+pub struct DirEntry {
+    entry: Entry,
+    /// First Cluster Of The Dir Holding The Entry
+    dir_cluster: u32,
+    /// Position Of The Short Item In Dir, Counted In Items
+    position: usize,
+    name: [u16; MAX_NAME_UNITS],
+    len: usize,
+    /// Count Of Items, LFN AND SFN
+    items: usize,
+    /// Order Of The Next LFN Item, Some(0) If The LFN Run Is Complete
+    expect: Option<usize>,
+}
+
+impl DirEntry {
+    pub(crate) fn new() -> Self {
+        Self {
+            entry: Entry::default(),
+            dir_cluster: 0,
+            position: 0,
+            name: [0; MAX_NAME_UNITS],
+            len: 0,
+            items: 0,
+            expect: None,
+        }
+    }
+
+    /// Feed Items Of Dir At dir_cluster In Order, position Is The Position Of item In Dir.
+    /// Return true When item Is A Short Item, Which Ends The Entry. A Complete LFN Run
+    /// Before It Gives The Name, Else Its Short Name Does. Volume Labels Are Skipped
+    pub(crate) fn collect(&mut self, item: Entry, dir_cluster: u32, position: usize, code_page: &dyn OemCodePage) -> bool {
+        if item.is_deleted() || item.is_volume_label() {
+            self.expect = None;
+            return false;
+        }
+
+        if let Some((units, n)) = item.get_lfn() {
+            let ord = item.count_of_name().unwrap();
+            if item.is_name_end().unwrap() {
+                self.len = ord.saturating_sub(1) * 13 + n;
+                self.expect = Some(ord).filter(|_| ord != 0 && self.len <= MAX_NAME_UNITS);
+                self.items = ord + 1;
+            }
+            if ord == 0 || self.expect != Some(ord) {
+                self.expect = None;
+                return false;
+            }
+            let at = (ord - 1) * 13;
+            let n = n.min(self.len - at);
+            self.name[at..at + n].copy_from_slice(&units[0..n]);
+            self.expect = Some(ord - 1);
+            return false;
+        }
+
+        if self.expect != Some(0) {
+            let (units, n) = item.get_sfn(code_page).unwrap();
+            self.name[0..n].copy_from_slice(&units[0..n]);
+            self.len = n;
+            self.items = 1;
+        }
+        self.entry = item;
+        self.dir_cluster = dir_cluster;
+        self.position = position;
+        self.expect = None;
+        true
+    }
+
+    /// Get Name, Unpaired Surrogates Become U+FFFD
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.name_utf16().iter().copied())
+            .map(|ch| ch.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// Get UTF-16 Units Of Name
+    pub fn name_utf16(&self) -> &[u16] {
+        &self.name[0..self.len]
+    }
+
+    /// Compare Name With value Case-Insensitively, Like Lookups Do
+    pub fn name_eq(&self, value: &str) -> bool {
+        eq_units_ignore_case(self.name_utf16().iter().copied(), value.encode_utf16())
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.entry.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.entry.is_file()
+    }
+
+    /// Length Of File In Bytes, 0 For Dir
+    pub fn length(&self) -> usize {
+        self.entry.length().unwrap_or(0)
+    }
+
+    pub(crate) fn entry(&self) -> Entry {
+        self.entry
+    }
+
+    pub(crate) fn dir_cluster(&self) -> u32 {
+        self.dir_cluster
+    }
+
+    /// Position Of The Short Item In Dir
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Count Of Items, LFN AND SFN
+    pub(crate) fn items(&self) -> usize {
+        self.items
+    }
+
+    /// Get UTF-16 Units Of Short Name If Entry Has A Long Name Too
+    pub(crate) fn alias_utf16(&self, code_page: &dyn OemCodePage) -> Option<([u16; 12], usize)> {
+        if self.items == 1 { return None; }
+        self.entry.get_sfn(code_page)
+    }
 }
@@ -0,0 +1,336 @@
+use core::cmp;
+use block_device::BlockDevice;
+use crate::bpb::BIOSParameterBlock;
+use crate::fat::FAT;
+use crate::file::FileError;
+
+/// A Run Of Contiguous Clusters In The Cluster Chain
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Extent {
+    /// Index Of The First Cluster In File
+    pub file_cluster: u32,
+    /// Cluster Number On Disk
+    pub disk_cluster: u32,
+    /// Count Of Clusters
+    pub len: u32,
+}
+
+impl Extent {
+    fn contains(&self, file_cluster: u32) -> bool {
+        file_cluster >= self.file_cluster
+            && file_cluster - self.file_cluster < self.len
+    }
+
+    fn is_next(&self, file_cluster: u32, disk_cluster: u32) -> bool {
+        self.file_cluster + self.len == file_cluster
+            && self.disk_cluster + self.len == disk_cluster
+    }
+}
+
+/// Fixed-Capacity Map From File Cluster To Disk Cluster.
+/// It Is Filled While Walking The Chain, N Extents At Most
+#[derive(Debug, Copy, Clone)]
+pub struct ExtentCache<const N: usize> {
+    extents: [Extent; N],
+    count: usize,
+    full: bool,
+    cursor: Option<(u32, u32)>,
+}
+
+impl<const N: usize> ExtentCache<N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            extents: [Extent::default(); N],
+            count: 0,
+            full: false,
+            cursor: None,
+        }
+    }
+
+    /// Extents Mapped Until Now
+    pub fn extents(&self) -> &[Extent] {
+        &self.extents[0..self.count]
+    }
+
+    /// Get Disk Cluster From File Cluster If Mapped
+    pub(crate) fn get(&self, file_cluster: u32) -> Option<u32> {
+        match self.cursor {
+            Some((f, d)) if f == file_cluster => Some(d),
+            _ => self.extents()
+                .iter()
+                .find(|e| e.contains(file_cluster))
+                .map(|e| e.disk_cluster + (file_cluster - e.file_cluster))
+        }
+    }
+
+    /// The Nearest Known Cluster Before file_cluster, Walking The Chain Starts From It.
+    /// The Last Visited Cluster Is Always Known, So Sequential Access Is Cheap
+    pub(crate) fn start(&self, file_cluster: u32) -> Option<(u32, u32)> {
+        let last = if self.count == 0 {
+            None
+        } else {
+            let e = &self.extents[self.count - 1];
+            Some((e.file_cluster + e.len - 1, e.disk_cluster + e.len - 1))
+        };
+
+        let before = |point: Option<(u32, u32)>| point.filter(|p| p.0 < file_cluster);
+        match (before(last), before(self.cursor)) {
+            (Some(a), Some(b)) => Some(if a.0 > b.0 { a } else { b }),
+            (a, b) => a.or(b)
+        }
+    }
+
+    /// Record Cluster, Must Follow The Last One.
+    /// If There Is No Room For New Extent, Stop Recording
+    pub(crate) fn push(&mut self, file_cluster: u32, disk_cluster: u32) {
+        self.cursor = Some((file_cluster, disk_cluster));
+        if self.full { return; }
+        if self.count != 0 {
+            let last = &mut self.extents[self.count - 1];
+            if last.is_next(file_cluster, disk_cluster) {
+                last.len += 1;
+                return;
+            }
+            if last.file_cluster + last.len != file_cluster { return; }
+        } else if file_cluster != 0 {
+            return;
+        }
+
+        if self.count == N {
+            self.full = true;
+        } else {
+            self.extents[self.count] = Extent {
+                file_cluster,
+                disk_cluster,
+                len: 1,
+            };
+            self.count += 1;
+        }
+    }
+
+    /// Forget All Extents, Needed When Chain Is Changed
+    pub(crate) fn clear(&mut self) {
+        self.count = 0;
+        self.full = false;
+        self.cursor = None;
+    }
+}
+
+/// Iterate Runs Of Contiguous Sectors Which Hold File Data,
+/// Item Is (first_sector, sector_count). Sectors Are Counted From
+/// The Start Of Device, Only Sectors Covered By File Length Are Given.
+/// If The Chain Is Broken OR Ends Before File Length, The Last Item Is Err
+pub struct SectorExtents<'a, T>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    pub(crate) bpb: &'a BIOSParameterBlock,
+    pub(crate) fat: FAT<T>,
+    pub(crate) left_sectors: usize,
+    pub(crate) pending: Option<u32>,
+}
+
+impl<'a, T> Iterator for SectorExtents<'a, T>
+    where T: BlockDevice + Clone + Copy,
+          <T as BlockDevice>::Error: core::fmt::Debug {
+    type Item = Result<(usize, usize), FileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left_sectors == 0 { return None; }
+        let spc = self.bpb.sector_per_cluster_usize();
+        let first = match self.pending.take() {
+            Some(cluster) => cluster,
+            None => match self.fat.next() {
+                Some(f) => f.current_cluster,
+                None => {
+                    self.left_sectors = 0;
+                    return Some(Err(FileError::CorruptChain));
+                }
+            }
+        };
+
+        let mut len = 1;
+        while len * spc < self.left_sectors {
+            match self.fat.next() {
+                Some(f) if f.current_cluster == first + len as u32 => len += 1,
+                Some(f) => {
+                    self.pending = Some(f.current_cluster);
+                    break;
+                }
+                None => {
+                    // the chain ends before file length, its sectors can't be trusted
+                    self.left_sectors = 0;
+                    return Some(Err(FileError::CorruptChain));
+                }
+            }
+        }
+
+        let sectors = cmp::min(len * spc, self.left_sectors);
+        self.left_sectors -= sectors;
+        let first_sector = self.bpb.offset(first) / self.bpb.byte_per_sector as usize;
+        Some(Ok((first_sector, sectors)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use crate::file::{SeekFrom, WriteType};
+    use crate::ram::Ram;
+    use crate::volume::Volume;
+
+    fn extent(file_cluster: u32, disk_cluster: u32, len: u32) -> Extent {
+        Extent { file_cluster, disk_cluster, len }
+    }
+
+    #[test]
+    fn runs_are_merged() {
+        let mut cache = ExtentCache::<4>::new();
+        for (i, cluster) in [10, 11, 12, 20, 21, 5].iter().enumerate() {
+            cache.push(i as u32, *cluster);
+        }
+        assert_eq!(cache.extents(), [extent(0, 10, 3), extent(3, 20, 2), extent(5, 5, 1)]);
+        assert_eq!((cache.get(0), cache.get(2), cache.get(4)), (Some(10), Some(12), Some(21)));
+        assert_eq!((cache.get(5), cache.get(6)), (Some(5), None));
+
+        // walking the chain starts from the end of mapped runs, mapped clusters need no walk
+        assert_eq!((cache.start(0), cache.start(4)), (None, None));
+        assert_eq!(cache.start(9), Some((5, 5)));
+
+        cache.clear();
+        assert_eq!((cache.extents().len(), cache.get(0), cache.start(9)), (0, None, None));
+    }
+
+    #[test]
+    fn full_cache_keeps_cursor() {
+        let mut cache = ExtentCache::<2>::new();
+        for (i, cluster) in [10, 11, 20, 30, 31].iter().enumerate() {
+            cache.push(i as u32, *cluster);
+        }
+        assert_eq!(cache.extents(), [extent(0, 10, 2), extent(2, 20, 1)]);
+        // the last visited cluster is known though no extent holds it
+        assert_eq!((cache.get(3), cache.get(4)), (None, Some(31)));
+        assert_eq!(cache.start(9), Some((4, 31)));
+
+        // a cluster which doesn't follow the last extent isn't recorded
+        let mut cache = ExtentCache::<2>::new();
+        cache.push(3, 40);
+        assert_eq!((cache.extents().len(), cache.get(3)), (0, Some(40)));
+    }
+
+    /// Volume With "a.bin" Of 12 Clusters In Runs Of 3, Interleaved With "b.bin"
+    fn fragmented() -> Ram {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file("a.bin").unwrap();
+        root.create_file("b.bin").unwrap();
+        for i in 0..4 {
+            let data: Vec<u8> = (0..3 * 512).map(|j| (i * 3 * 512 + j) as u8 ^ (j / 512) as u8).collect();
+            root.open_file("a.bin").unwrap().write(&data, WriteType::Append).unwrap();
+            root.open_file("b.bin").unwrap().write(&[0xBB; 3 * 512], WriteType::Append).unwrap();
+        }
+        ram
+    }
+
+    fn expected(offset: usize) -> u8 {
+        offset as u8 ^ (offset % (3 * 512) / 512) as u8
+    }
+
+    #[test]
+    fn positional_io_through_cache() {
+        let ram = fragmented();
+        let volume = Volume::new(ram);
+        let root = volume.root_dir();
+        let mut file = root.open_file("a.bin").unwrap().with_extent_cache::<8>();
+        assert_eq!(file.length(), 12 * 512);
+
+        // from the end back, each read crosses a gap between runs
+        let mut buf = [0; 700];
+        for offset in (0..12 * 512 - 700).rev().step_by(450) {
+            assert_eq!(file.read_at(offset, &mut buf).unwrap(), 700);
+            assert!(buf.iter().enumerate().all(|(i, &b)| b == expected(offset + i)));
+        }
+        let runs = file.extent_cache().extents().len();
+        assert!(runs >= 4);
+        assert_eq!(file.extent_cache().extents().iter().map(|e| e.len).sum::<u32>(), 12);
+
+        file.write_at(5 * 512 - 3, &[0xEE; 6]).unwrap();
+        assert_eq!(file.read_at(5 * 512 - 4, &mut buf[0..8]).unwrap(), 8);
+        assert_eq!(buf[0..8], [expected(5 * 512 - 4), 0xEE, 0xEE, 0xEE, 0xEE, 0xEE, 0xEE, expected(5 * 512 + 3)]);
+        assert_eq!(root.open_file("b.bin").unwrap().length(), 12 * 512);
+    }
+
+    #[test]
+    fn seek_past_end() {
+        let volume = Volume::new(fragmented());
+        let mut file = volume.root_dir().open_file("a.bin").unwrap().with_extent_cache::<2>();
+        let length = file.length();
+
+        assert_eq!(file.seek(SeekFrom::Start(7 * 512 + 1)).unwrap(), 7 * 512 + 1);
+        let mut buf = [0; 4];
+        assert_eq!(file.read_next(&mut buf).unwrap(), 4);
+        assert_eq!(buf[0], expected(7 * 512 + 1));
+        assert_eq!(file.seek(SeekFrom::Current(-5)).unwrap(), 7 * 512);
+
+        // the end itself is a position, nothing can be read from it
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), length);
+        assert_eq!(file.read_next(&mut buf).unwrap(), 0);
+        assert!(matches!(file.seek(SeekFrom::End(1)), Err(FileError::OutOfRange)));
+        assert!(matches!(file.seek(SeekFrom::Start(length + 1)), Err(FileError::OutOfRange)));
+        assert!(matches!(file.seek(SeekFrom::Current(-(length as isize) - 1)), Err(FileError::OutOfRange)));
+        assert!(matches!(file.write_at(length + 1, b"x"), Err(FileError::OutOfRange)));
+        assert_eq!(file.read_at(length + 100, &mut buf).unwrap(), 0);
+        assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), length);
+    }
+
+    #[test]
+    fn sector_extents() {
+        let ram = fragmented();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        let file = root.open_file("a.bin").unwrap();
+        let runs: Vec<(usize, usize)> = file.extents().map(|run| run.unwrap()).collect();
+        assert_eq!(runs.iter().map(|run| run.1).sum::<usize>(), 12);
+        assert!(runs.len() >= 4);
+        assert!(!file.is_contiguous().unwrap());
+
+        // the runs can be read without the file system
+        let mut offset = 0;
+        for (first, count) in runs {
+            let mut buf = std::vec![0; count * 512];
+            ram.read(&mut buf, first * 512, count).unwrap();
+            assert!(buf.iter().enumerate().all(|(i, &b)| b == expected(offset + i)));
+            offset += buf.len();
+        }
+
+        // only sectors covered by length are given
+        root.create_file("c.bin").unwrap();
+        let mut file = root.open_file("c.bin").unwrap();
+        assert_eq!((file.extents().count(), file.is_contiguous().unwrap()), (0, true));
+        file.write(&[0xCC; 1000], WriteType::Append).unwrap();
+        assert_eq!(file.extents().map(|run| run.unwrap().1).collect::<Vec<_>>(), [2]);
+        assert!(file.is_contiguous().unwrap());
+    }
+
+    #[test]
+    fn sector_extents_of_broken_chain() {
+        let ram = fragmented();
+        let volume = Volume::new(ram);
+        let file = volume.root_dir().open_file("a.bin").unwrap();
+        let runs: Vec<(usize, usize)> = file.extents().take(2).map(|run| run.unwrap()).collect();
+
+        // the chain ends after the second run, data region starts at sector 64 with cluster 2
+        let (first, count) = runs[1];
+        let cluster = (first + count - 1 - 64 + 2) as u32;
+        let mut fat = [0; 512];
+        ram.read(&mut fat, 32 * 512, 1).unwrap();
+        fat[cluster as usize * 4..cluster as usize * 4 + 4].copy_from_slice(&0x0FFF_FFFF_u32.to_le_bytes());
+        ram.write(&fat, 32 * 512, 1).unwrap();
+
+        // the run the chain ends in can't be trusted, so it is not given
+        let broken: Vec<_> = file.extents().collect();
+        assert!(matches!(broken[..], [Ok(run), Err(FileError::CorruptChain)] if run == runs[0]));
+        assert!(matches!(file.is_contiguous(), Err(FileError::CorruptChain)));
+    }
+}
@@ -1,89 +1,463 @@
 use block_device::BlockDevice;
 use crate::BUFFER_SIZE;
+use crate::bpb::BIOSParameterBlock;
 use crate::tool::read_le_u32;
 
+/// Value Of The Last Cluster In Chain
+pub(crate) const END_OF_CHAIN: u32 = 0x0FFFFFFF;
+
+/// Value Of Bad Cluster
+pub(crate) const BAD_CLUSTER: u32 = 0x0FFFFFF7;
+
+/// Upper 4 Bits Of Entry Are Reserved, Must Be Kept When Writing
+const ENTRY_MASK: u32 = 0x0FFFFFFF;
+
+/// Kind Of FAT Entry
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub enum FATEntry {
+    /// Cluster Is Not Used
+    Free,
+    /// Cluster Links To The Next Cluster In Chain
+    Next(u32),
+    /// Cluster Is Marked Bad
+    Bad,
+    /// Reserved Value, Never A Valid Link
+    Reserved,
+    /// Last Cluster In Chain, 0x0FFFFFF8..=0x0FFFFFFF
+    EndOfChain,
+}
+
+impl From<u32> for FATEntry {
+    fn from(value: u32) -> Self {
+        match value & ENTRY_MASK {
+            0 => FATEntry::Free,
+            1 => FATEntry::Reserved,
+            n @ 2..=0x0FFFFFEF => FATEntry::Next(n),
+            BAD_CLUSTER => FATEntry::Bad,
+            0x0FFFFFF8..=0x0FFFFFFF => FATEntry::EndOfChain,
+            _ => FATEntry::Reserved,
+        }
+    }
+}
+
+/// Get Entry Of Cluster From The FAT Sector Which Holds It
+fn get_entry(buffer: &[u8], cluster: u32) -> FATEntry {
+    let i = cluster as usize % (BUFFER_SIZE / 4) * 4;
+    FATEntry::from(read_le_u32(&buffer[i..i + 4]))
+}
+
+/// Set Entry Of Cluster In The FAT Sector Which Holds It, Reserved Bits Are Kept
+fn set_entry(buffer: &mut [u8], cluster: u32, value: u32) {
+    let i = cluster as usize % (BUFFER_SIZE / 4) * 4;
+    let value = read_le_u32(&buffer[i..i + 4]) & !ENTRY_MASK | value & ENTRY_MASK;
+    buffer[i..i + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Define FATError
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub enum FATError {
+    DiskFull,
+    CorruptChain,
+    /// Device Failed To Write A Sector
+    WriteFailed,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct FAT<T>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
     device: T,
     fat_offset: usize,
+    fs_info: Option<usize>,
+    free_change: i64,
+    next_free: Option<u32>,
+    cluster_end: u32,
     start_cluster: u32,
     previous_cluster: u32,
     pub(crate) current_cluster: u32,
     next_cluster: Option<u32>,
+    steps: u32,
+    corrupt: bool,
     buffer: [u8; BUFFER_SIZE],
 }
 
 impl<T> FAT<T>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
-    pub(crate) fn new(cluster: u32, device: T, fat_offset: usize) -> Self {
+    pub(crate) fn new(cluster: u32, device: T, bpb: &BIOSParameterBlock) -> Self {
         Self {
             device,
-            fat_offset,
+            fat_offset: bpb.fat1(),
+            fs_info: bpb.fs_info(),
+            free_change: 0,
+            next_free: None,
+            cluster_end: bpb.cluster_count() + 2,
             start_cluster: cluster,
             previous_cluster: 0,
             current_cluster: 0,
             next_cluster: None,
+            steps: 0,
+            corrupt: false,
             buffer: [0; BUFFER_SIZE],
         }
     }
 
-    pub(crate) fn blank_cluster(&mut self) -> u32 {
-        let mut cluster = 0;
-        let mut done = false;
+    /// Allocate count Clusters As A Chain, Return The First One.
+    /// Search From The Cluster After near If It Is Blank, Else From The Next Free Hint
+    /// In FSInfo, So The Chain Is Contiguous When Free Space Is. Nothing Is Changed
+    /// If There Are Not Enough Blank Clusters, A Failed Write Can Leak Clusters
+    pub(crate) fn allocate(&mut self, count: u32, near: Option<u32>) -> Result<u32, FATError> {
+        let start = match near {
+            Some(c) if self.is_valid(c + 1) && self.read(c + 1) == FATEntry::Free => c + 1,
+            _ => self.next_free_hint()
+        };
+
+        let per_block = BUFFER_SIZE / 4;
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut block = None;
+        let mut dirty = false;
+        let mut first = None;
+        let mut previous: Option<u32> = None;
+        let mut found = 0;
+        let mut written = Ok(());
+
+        for cluster in (start..self.cluster_end).chain(2..start) {
+            if found == count { break; }
+            let b = cluster as usize / per_block;
+            if block != Some(b) {
+                if let (Some(block), true) = (block, dirty) { written = self.write_block(&buffer, block); }
+                if written.is_err() { break; }
+                self.read_block(&mut buffer, b);
+                block = Some(b);
+                dirty = false;
+            }
+
+            if get_entry(&buffer, cluster) != FATEntry::Free { continue; }
+
+            set_entry(&mut buffer, cluster, END_OF_CHAIN);
+            dirty = true;
+            match previous {
+                Some(p) if p as usize / per_block == b => set_entry(&mut buffer, p, cluster),
+                Some(p) => written = self.write(p, cluster),
+                None => first = Some(cluster)
+            }
+            if written.is_err() { break; }
+            previous = Some(cluster);
+            found += 1;
+        }
+        if let (Some(block), true, Ok(_)) = (block, dirty, written) { written = self.write_block(&buffer, block); }
+
+        match (first, previous, written) {
+            (Some(first), Some(last), Ok(_)) if found == count => {
+                self.change_fs_info(-(count as i64), Some(last + 1));
+                Ok(first)
+            }
+            (_, _, written) => {
+                if let Some(first) = first { let _ = self.release(first); }
+                written.and(Err(FATError::DiskFull))
+            }
+        }
+    }
+
+    /// Allocate count Contiguous Clusters As A Chain, Return The First One.
+    /// The Run Right After near Is Preferred
+    pub(crate) fn allocate_contiguous(&mut self, count: u32, near: Option<u32>) -> Result<u32, FATError> {
+        let from = match near {
+            Some(c) => c + 1,
+            None => self.next_free_hint()
+        };
+        let first = self.blank_run(count, from).ok_or(FATError::DiskFull)?;
+
+        for cluster in first..first + count - 1 {
+            self.write(cluster, cluster + 1)?;
+        }
+        self.write(first + count - 1, END_OF_CHAIN)?;
+        self.change_fs_info(-(count as i64), Some(first + count));
+        Ok(first)
+    }
+
+    /// Free All Clusters In Chain From start
+    pub(crate) fn free(&mut self, start: u32) -> Result<(), FATError> {
+        if !self.is_valid(start) { return Ok(()); }
+        let count = self.release(start)?;
+        self.change_fs_info(count as i64, None);
+        Ok(())
+    }
+
+    /// Mark Blank Cluster Bad, Allocating Skips It Since Then
+    pub(crate) fn mark_bad(&mut self, cluster: u32) -> Result<(), FATError> {
+        self.write(cluster, BAD_CLUSTER)?;
+        self.change_fs_info(-1, None);
+        Ok(())
+    }
 
-        for block in 0.. {
-            self.device.read(&mut self.buffer,
-                             self.fat_offset + block * BUFFER_SIZE,
-                             1).unwrap();
-            for i in (0..BUFFER_SIZE).step_by(4) {
-                if read_le_u32(&self.buffer[i..i + 4]) == 0 {
-                    done = true;
-                    break;
-                } else { cluster += 1; }
+    /// Find The First Blank Cluster From from
+    pub(crate) fn next_blank(&mut self, from: u32) -> Option<u32> {
+        self.find_blank_run(1, from, self.cluster_end)
+    }
+
+    /// Call f With Each Cluster Of Data Region AND Its Entry, Each FAT Sector Is Read Once
+    pub(crate) fn for_each_entry<F: FnMut(u32, FATEntry)>(&mut self, mut f: F) {
+        let per_block = (BUFFER_SIZE / 4) as u32;
+        for cluster in 2..self.cluster_end {
+            if cluster == 2 || cluster % per_block == 0 {
+                self.device.read(&mut self.buffer,
+                                 self.fat_offset + (cluster / per_block) as usize * BUFFER_SIZE,
+                                 1).unwrap();
             }
-            if done { break; }
+            f(cluster, get_entry(&self.buffer, cluster));
+        }
+    }
+
+    /// Count Clusters Linked One After Another From cluster, The First One Included, At Most max.
+    /// Each FAT Sector Is Read Once
+    pub(crate) fn run_length(&mut self, cluster: u32, max: u32) -> u32 {
+        let per_block = (BUFFER_SIZE / 4) as u32;
+        let mut block = None;
+        let mut len = 1;
+        while len < max {
+            let c = cluster + len - 1;
+            if block != Some(c / per_block) {
+                block = Some(c / per_block);
+                self.device.read(&mut self.buffer,
+                                 self.fat_offset + (c / per_block) as usize * BUFFER_SIZE,
+                                 1).unwrap();
+            }
+            if get_entry(&self.buffer, c) != FATEntry::Next(c + 1) || !self.is_valid(c + 1) { break; }
+            len += 1;
+        }
+        len
+    }
+
+    /// Set All Clusters In Chain From start Blank, Return Count, FSInfo Is Not Updated
+    fn release(&mut self, start: u32) -> Result<u32, FATError> {
+        let mut count = 0;
+        for mut f in FAT::new_raw(start, *self) {
+            f.write(f.current_cluster, 0)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Copy Of FAT Which Iterates From Cluster
+    fn new_raw(cluster: u32, fat: Self) -> Self {
+        Self {
+            start_cluster: cluster,
+            previous_cluster: 0,
+            current_cluster: 0,
+            next_cluster: None,
+            steps: 0,
+            corrupt: false,
+            ..fat
+        }
+    }
+
+    /// Find count Contiguous Blank Clusters, Return The First One.
+    /// Search From from First, Then From The Start Of FAT
+    fn blank_run(&mut self, count: u32, from: u32) -> Option<u32> {
+        let end = self.cluster_end;
+        let from = if self.is_valid(from) { from } else { 2 };
+        self.find_blank_run(count, from, end)
+            .or_else(|| self.find_blank_run(count, 2, (from + count - 1).min(end)))
+    }
+
+    fn find_blank_run(&mut self, count: u32, from: u32, end: u32) -> Option<u32> {
+        let per_block = (BUFFER_SIZE / 4) as u32;
+        let mut block = None;
+        let mut first = from;
+
+        for cluster in from..end {
+            if block != Some(cluster / per_block) {
+                block = Some(cluster / per_block);
+                self.device.read(&mut self.buffer,
+                                 self.fat_offset + (cluster / per_block) as usize * BUFFER_SIZE,
+                                 1).unwrap();
+            }
+
+            if get_entry(&self.buffer, cluster) != FATEntry::Free {
+                first = cluster + 1;
+            } else if cluster + 1 - first == count {
+                return Some(first);
+            }
+        }
+        None
+    }
+
+    /// Check If The Cluster Is In Data Region
+    pub(crate) fn is_valid(&self, cluster: u32) -> bool {
+        (2..self.cluster_end).contains(&cluster)
+    }
+
+    /// Get Next Free Hint From FSInfo, 2 If Unknown
+    fn next_free_hint(&self) -> u32 {
+        if let Some(hint) = self.next_free.filter(|&c| self.is_valid(c)) { return hint; }
+        let mut buffer = [0; BUFFER_SIZE];
+        match self.read_fs_info(&mut buffer) {
+            Some(offset) => {
+                let hint = read_le_u32(&buffer[offset + 0x1EC..offset + 0x1F0]);
+                if self.is_valid(hint) { hint } else { 2 }
+            }
+            None => 2
+        }
+    }
+
+    /// Read Sector Of FSInfo, Return Offset Of FSInfo In Buffer If Signatures Are Right
+    fn read_fs_info(&self, buffer: &mut [u8; BUFFER_SIZE]) -> Option<usize> {
+        let fs_info = self.fs_info?;
+        let block = fs_info / BUFFER_SIZE * BUFFER_SIZE;
+        let offset = fs_info - block;
+        self.device.read(buffer, block, 1).unwrap();
+
+        if read_le_u32(&buffer[offset..offset + 4]) == 0x41615252
+            && read_le_u32(&buffer[offset + 0x1E4..offset + 0x1E8]) == 0x61417272 {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Keep Change Of Free Count And Next Free Hint, update_fs_info() Writes Them
+    fn change_fs_info(&mut self, free_change: i64, next_free: Option<u32>) {
+        self.free_change += free_change;
+        if next_free.is_some() { self.next_free = next_free; }
+    }
+
+    /// Write Kept Changes To FSInfo. FSInfo Is Only A Hint,
+    /// So It Is Written After FAT And Directory Entries
+    pub(crate) fn update_fs_info(&mut self) -> Result<(), FATError> {
+        let (free_change, next_free) = (self.free_change, self.next_free.take());
+        self.free_change = 0;
+        if free_change == 0 && next_free.is_none() { return Ok(()); }
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let offset = match self.read_fs_info(&mut buffer) {
+            Some(offset) => offset,
+            None => return Ok(())
+        };
+
+        let free_count = read_le_u32(&buffer[offset + 0x1E8..offset + 0x1EC]);
+        if free_count <= self.cluster_end - 2 {
+            let free_count = (free_count as i64 + free_change)
+                .max(0)
+                .min((self.cluster_end - 2) as i64) as u32;
+            buffer[offset + 0x1E8..offset + 0x1EC].copy_from_slice(&free_count.to_le_bytes());
+        }
+        if let Some(next_free) = next_free {
+            let next_free = if self.is_valid(next_free) { next_free } else { 2 };
+            buffer[offset + 0x1EC..offset + 0x1F0].copy_from_slice(&next_free.to_le_bytes());
         }
-        cluster
+
+        let block = self.fs_info.unwrap() / BUFFER_SIZE * BUFFER_SIZE;
+        self.device.write(&buffer, block, 1).map_err(|_| FATError::WriteFailed)
+    }
+
+    fn read_block(&self, buffer: &mut [u8], block: usize) {
+        self.device.read(buffer, self.fat_offset + block * BUFFER_SIZE, 1).unwrap();
+    }
+
+    fn write_block(&self, buffer: &[u8], block: usize) -> Result<(), FATError> {
+        self.device.write(buffer, self.fat_offset + block * BUFFER_SIZE, 1).map_err(|_| FATError::WriteFailed)
     }
 
-    pub(crate) fn write(&mut self, cluster: u32, value: u32) {
+    /// Read The Entry Of Cluster In FAT
+    pub(crate) fn read(&mut self, cluster: u32) -> FATEntry {
+        let offset = (cluster as usize) * 4;
+        let block_offset = offset / BUFFER_SIZE;
+
+        self.device.read(&mut self.buffer,
+                         self.fat_offset + block_offset * BUFFER_SIZE,
+                         1).unwrap();
+        get_entry(&self.buffer, cluster)
+    }
+
+    /// Write The Value Of Cluster In FAT, Reserved Bits Are Kept
+    pub(crate) fn write(&mut self, cluster: u32, value: u32) -> Result<(), FATError> {
         let offset = (cluster as usize) * 4;
         let block_offset = offset / BUFFER_SIZE;
-        let offset_left = offset % BUFFER_SIZE;
         let offset = self.fat_offset + block_offset * BUFFER_SIZE;
-        let mut value: [u8; 4] = value.to_be_bytes();
-        value.reverse();
 
         self.device.read(&mut self.buffer,
                          offset,
                          1).unwrap();
-        self.buffer[offset_left..offset_left + 4].copy_from_slice(&value);
+        set_entry(&mut self.buffer, cluster, value);
         self.device.write(&self.buffer,
                           offset,
-                          1).unwrap();
-    }
-
-    pub(crate) fn refresh(&mut self, start_cluster: u32) {
-        self.current_cluster = 0;
-        self.start_cluster = start_cluster;
+                          1).map_err(|_| FATError::WriteFailed)
     }
 
     pub(crate) fn previous(&mut self) {
         if self.current_cluster != 0 {
             self.next_cluster = Some(self.current_cluster);
             self.current_cluster = self.previous_cluster;
+            self.steps = self.steps.saturating_sub(1);
         }
     }
 
+    /// Err If Iterating Stopped At A Link Out Of Data Region,
+    /// A Link To Blank OR Bad Cluster, OR A Loop
+    pub(crate) fn check(&self) -> Result<(), FATError> {
+        if self.corrupt { Err(FATError::CorruptChain) } else { Ok(()) }
+    }
+
     pub(crate) fn next_is_none(&self) -> bool {
         self.next_cluster.is_none()
     }
 
-    fn current_cluster_usize(&self) -> usize {
-        self.current_cluster as usize
+    /// Step count Clusters Along Chain Which next() Has Started,
+    /// Reading Each FAT Sector Once While The Links Stay In It. Return Count Of Steps Made
+    pub(crate) fn advance(&mut self, count: usize) -> usize {
+        let mut block = None;
+        for done in 0..count {
+            if !self.step(&mut block) { return done; }
+        }
+        count
+    }
+
+    /// Step Along Chain Which next() Has Started Until cluster, Like advance.
+    /// Return Count Of Steps Made, None If cluster Is Not In The Rest Of Chain
+    pub(crate) fn advance_to(&mut self, cluster: u32) -> Option<usize> {
+        let mut block = None;
+        let mut steps = 0;
+        while self.current_cluster != cluster {
+            if !self.step(&mut block) { return None; }
+            steps += 1;
+        }
+        Some(steps)
+    }
+
+    /// Step To The Next Cluster, Reading Its FAT Sector Unless block Is Already Held
+    fn step(&mut self, block: &mut Option<usize>) -> bool {
+        let next_cluster = match self.next_cluster {
+            Some(c) if self.current_cluster != 0 => c,
+            _ => return false
+        };
+        self.previous_cluster = self.current_cluster;
+        self.current_cluster = next_cluster;
+        self.steps += 1;
+
+        let b = next_cluster as usize * 4 / BUFFER_SIZE;
+        if *block != Some(b) {
+            self.device.read(&mut self.buffer, self.fat_offset + b * BUFFER_SIZE, 1).unwrap();
+            *block = Some(b);
+        }
+        let entry = get_entry(&self.buffer, next_cluster);
+        self.link(entry);
+        true
+    }
+
+    /// Keep The Link Read From The Entry Of Current Cluster, None At The End Of Chain
+    fn link(&mut self, entry: FATEntry) -> Option<u32> {
+        // no chain is longer than the count of clusters, a longer one loops
+        self.next_cluster = match entry {
+            FATEntry::Next(next_cluster)
+            if self.is_valid(next_cluster) && self.steps + 1 < self.cluster_end - 2 => Some(next_cluster),
+            FATEntry::EndOfChain => None,
+            _ => {
+                self.corrupt = true;
+                None
+            }
+        };
+        self.next_cluster
     }
 }
 
@@ -94,33 +468,24 @@ impl<T> Iterator for FAT<T>
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.current_cluster == 0 {
+            if !self.is_valid(self.start_cluster) {
+                self.corrupt = true;
+                return None;
+            }
             self.current_cluster = self.start_cluster;
         } else {
-            let next_cluster = self.next_cluster;
-            if next_cluster.is_some() {
-                self.previous_cluster = self.current_cluster;
-                self.current_cluster = next_cluster.unwrap();
-            } else {
-                return None;
+            match self.next_cluster {
+                Some(next_cluster) => {
+                    self.previous_cluster = self.current_cluster;
+                    self.current_cluster = next_cluster;
+                    self.steps += 1;
+                }
+                None => return None
             }
         }
 
-        let offset = self.current_cluster_usize() * 4;
-        let block_offset = offset / BUFFER_SIZE;
-        let offset_left = offset % BUFFER_SIZE;
-
-        self.device.read(&mut self.buffer,
-                         self.fat_offset + block_offset * BUFFER_SIZE,
-                         1).unwrap();
-
-        let next_cluster = read_le_u32(&self.buffer[offset_left..offset_left + 4]);
-        let next_cluster = if next_cluster == 0x0FFFFFFF {
-            None
-        } else {
-            Some(next_cluster)
-        };
-
-        self.next_cluster = next_cluster;
+        let entry = self.read(self.current_cluster);
+        let next_cluster = self.link(entry);
 
         Some(Self {
             next_cluster,
@@ -128,3 +493,160 @@ impl<T> Iterator for FAT<T>
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use crate::ram::Ram;
+
+    #[test]
+    fn entry_from_value() {
+        assert_eq!(FATEntry::from(0), FATEntry::Free);
+        assert_eq!(FATEntry::from(1), FATEntry::Reserved);
+        assert_eq!(FATEntry::from(2), FATEntry::Next(2));
+        assert_eq!(FATEntry::from(0x0FFFFFEF), FATEntry::Next(0x0FFFFFEF));
+        assert_eq!(FATEntry::from(0x0FFFFFF0), FATEntry::Reserved);
+        assert_eq!(FATEntry::from(BAD_CLUSTER), FATEntry::Bad);
+        assert_eq!(FATEntry::from(0x0FFFFFF8), FATEntry::EndOfChain);
+        assert_eq!(FATEntry::from(END_OF_CHAIN), FATEntry::EndOfChain);
+    }
+
+    #[test]
+    fn upper_bits_are_masked() {
+        assert_eq!(FATEntry::from(0xF000_0000), FATEntry::Free);
+        assert_eq!(FATEntry::from(0x1000_0005), FATEntry::Next(5));
+        assert_eq!(FATEntry::from(0xA000_0000 | BAD_CLUSTER), FATEntry::Bad);
+        assert_eq!(FATEntry::from(0xFFFF_FFFF), FATEntry::EndOfChain);
+    }
+
+    #[test]
+    fn set_entry_keeps_upper_bits() {
+        let mut buffer = [0; BUFFER_SIZE];
+        buffer[12..16].copy_from_slice(&0xF000_0000u32.to_le_bytes());
+        set_entry(&mut buffer, 3, 0xFFFF_0009);
+        assert_eq!(read_le_u32(&buffer[12..16]), 0xFFFF_0009);
+        set_entry(&mut buffer, 3, 0);
+        assert_eq!(read_le_u32(&buffer[12..16]), 0xF000_0000);
+        assert_eq!(get_entry(&buffer, 3), FATEntry::Free);
+        // entries of the next FAT sector sit at the same offsets
+        assert_eq!(get_entry(&buffer, 3 + (BUFFER_SIZE / 4) as u32), FATEntry::Free);
+    }
+
+    fn chain(fat: FAT<Ram>, first: u32) -> Vec<u32> {
+        FAT::new_raw(first, fat).map(|f| f.current_cluster).collect()
+    }
+
+    /// FAT Sectors Of The Image
+    fn raw(ram: Ram) -> Vec<u8> {
+        let mut buf = std::vec![0; 32 * BUFFER_SIZE];
+        ram.read(&mut buf, 32 * BUFFER_SIZE, 32).unwrap();
+        buf
+    }
+
+    #[test]
+    fn next_fit() {
+        let (ram, bpb) = (Ram::format(), Ram::bpb());
+        let mut fat = FAT::new(2, ram, &bpb);
+        let a = fat.allocate(3, None).unwrap();
+        let b = fat.allocate(2, None).unwrap();
+        assert_eq!((chain(fat, a), chain(fat, b)), (std::vec![3, 4, 5], std::vec![6, 7]));
+
+        // a is followed by b, so growing a goes on from the hint
+        assert_eq!(chain(fat, fat.allocate(2, Some(5)).unwrap()), [8, 9]);
+        assert_eq!(chain(fat, fat.allocate(1, Some(9)).unwrap()), [10]);
+        fat.update_fs_info().unwrap();
+
+        // the hint is kept in FSInfo for the next FAT
+        let mut fat = FAT::new(2, ram, &bpb);
+        fat.free(a).unwrap();
+        assert_eq!(chain(fat, fat.allocate(2, None).unwrap()), [11, 12]);
+        fat.update_fs_info().unwrap();
+        let mut fs_info = [0; BUFFER_SIZE];
+        ram.read(&mut fs_info, BUFFER_SIZE, 1).unwrap();
+        assert_eq!(read_le_u32(&fs_info[0x1EC..0x1F0]), 13);
+    }
+
+    #[test]
+    fn search_wraps_at_end_of_fat() {
+        let (ram, bpb) = (Ram::format(), Ram::bpb());
+        let mut fat = FAT::new(2, ram, &bpb);
+        let end = fat.cluster_end;
+        fat.next_free = Some(end - 2);
+        let first = fat.allocate(4, None).unwrap();
+        assert_eq!(chain(fat, first), [end - 2, end - 1, 3, 4]);
+        fat.free(first).unwrap();
+        assert_eq!(ram.free_clusters(), (end - 3) as usize);
+    }
+
+    #[test]
+    fn contiguous_run_skips_holes() {
+        let (ram, bpb) = (Ram::format(), Ram::bpb());
+        let mut fat = FAT::new(2, ram, &bpb);
+        let a = fat.allocate(3, None).unwrap();
+        let b = fat.allocate(3, None).unwrap();
+        fat.allocate(1, None).unwrap();
+        fat.free(a).unwrap();
+        fat.free(b).unwrap();
+        fat.next_free = Some(2);
+
+        // 3..=8 is blank but 9 isn't, a run of 7 starts at 10
+        let six = fat.allocate_contiguous(6, None).unwrap();
+        assert_eq!(chain(fat, six), [3, 4, 5, 6, 7, 8]);
+        assert_eq!(chain(fat, fat.allocate_contiguous(7, Some(2)).unwrap()), (10..17).collect::<Vec<_>>());
+
+        // a chain which needn't be contiguous fills the hole first
+        fat.free(six).unwrap();
+        fat.next_free = Some(2);
+        assert_eq!(chain(fat, fat.allocate(8, None).unwrap()), [3, 4, 5, 6, 7, 8, 17, 18]);
+    }
+
+    #[test]
+    fn disk_full_changes_nothing() {
+        let (ram, bpb) = (Ram::format(), Ram::bpb());
+        let mut fat = FAT::new(2, ram, &bpb);
+        fat.allocate(100, None).unwrap();
+        fat.update_fs_info().unwrap();
+        let before = raw(ram);
+        let free = ram.free_clusters() as u32;
+
+        assert_eq!(fat.allocate(free + 1, None), Err(FATError::DiskFull));
+        assert_eq!(fat.allocate_contiguous(free + 1, None), Err(FATError::DiskFull));
+        assert!(raw(ram) == before);
+        let first = fat.allocate(free, None).unwrap();
+        assert_eq!(chain(fat, first).len(), free as usize);
+        assert_eq!(fat.allocate(1, None), Err(FATError::DiskFull));
+    }
+
+    /// Clusters Of Chain AND Whether It Ended Well
+    fn walk(fat: FAT<Ram>, first: u32) -> (Vec<u32>, bool) {
+        let mut chain = FAT::new_raw(first, fat);
+        let clusters = chain.by_ref().map(|f| f.current_cluster).collect();
+        (clusters, chain.check().is_ok())
+    }
+
+    #[test]
+    fn corrupt_chains_end() {
+        let (ram, bpb) = (Ram::format(), Ram::bpb());
+        let mut fat = FAT::new(2, ram, &bpb);
+        let first = fat.allocate(3, None).unwrap();
+        assert_eq!(walk(fat, first), (std::vec![3, 4, 5], true));
+
+        // a loop is cut after as many steps as there are clusters
+        fat.write(5, 3).unwrap();
+        let (clusters, ok) = walk(fat, first);
+        assert_eq!((clusters.len(), ok), ((fat.cluster_end - 2) as usize, false));
+
+        // links out of data region, to a blank OR a bad cluster
+        fat.write(4, 0x0FFF_FF00).unwrap();
+        assert_eq!(walk(fat, first), (std::vec![3, 4], false));
+        fat.write(4, 1).unwrap();
+        assert_eq!(walk(fat, first), (std::vec![3, 4], false));
+        fat.write(4, 100).unwrap();
+        assert_eq!(walk(fat, first), (std::vec![3, 4, 100], false));
+        fat.write(100, BAD_CLUSTER).unwrap();
+        assert_eq!(walk(fat, first), (std::vec![3, 4, 100], false));
+        assert_eq!(walk(fat, 0), (std::vec![], false));
+        assert_eq!(walk(fat, fat.cluster_end), (std::vec![], false));
+    }
+}
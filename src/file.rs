@@ -2,36 +2,160 @@ use core::cmp;
 use block_device::BlockDevice;
 use crate::bpb::BIOSParameterBlock;
 use crate::entry::Entry;
-use crate::fat::FAT;
+use crate::fat::{
+    FAT,
+    FATError,
+    FATEntry,
+    END_OF_CHAIN,
+};
 use crate::BUFFER_SIZE;
 use crate::dir::DirIter;
 use crate::tool::get_needed_sector;
+use crate::extent::{
+    ExtentCache,
+    SectorExtents,
+};
 
 /// Define FileError
 #[derive(Debug)]
 pub enum FileError {
     BufTooSmall,
     WriteError,
+    NotReadable,
+    NotWritable,
+    OutOfRange,
+    NoContiguousSpace,
+    DiskFull,
+    CorruptChain,
+}
+
+impl From<FATError> for FileError {
+    fn from(e: FATError) -> Self {
+        match e {
+            FATError::DiskFull => FileError::DiskFull,
+            FATError::CorruptChain => FileError::CorruptChain,
+            FATError::WriteFailed => FileError::WriteError,
+        }
+    }
 }
 
 /// Define WriteType
 pub enum WriteType {
+    /// Write From Start Of File In Place, Then Free Clusters Past The New Length.
+    /// Not Power-Loss Safe For Content, Use CopyOnWrite For That
     OverWritten,
     Append,
+    /// Write To New Clusters, Then Switch Dir Item To Them And Free The Old Ones.
+    /// Old Content Stays Whole Until New Data Is Written
+    CopyOnWrite,
+}
+
+/// Define SeekFrom, The Position To Seek
+#[derive(Debug, Copy, Clone)]
+pub enum SeekFrom {
+    Start(usize),
+    End(isize),
+    Current(isize),
+}
+
+/// Options To Open A File Through Dir::open, Like std::fs::OpenOptions
+#[derive(Debug, Copy, Clone, Default)]
+pub struct OpenOptions {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) append: bool,
+    pub(crate) truncate: bool,
+    pub(crate) create: bool,
+    pub(crate) create_new: bool,
+    pub(crate) keep_preallocated: bool,
+}
+
+impl OpenOptions {
+    /// All Options Are Set To false
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow To Read The File
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    /// Allow To Write The File
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every Write Appends To The End Of The File, Implies write
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Cut The File To Zero Length If It Exists, Needs write
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Create The File If It Doesn't Exist, Needs write OR append
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Always Create A New File, Fail If It Exists, Needs write OR append
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Keep Clusters Reserved By File::preallocate When File Is Closed,
+    /// Otherwise The Unused Ones Are Released
+    pub fn keep_preallocated(&mut self, keep_preallocated: bool) -> &mut Self {
+        self.keep_preallocated = keep_preallocated;
+        self
+    }
+
+    /// Options Used By Dir::open_file, Read And Write
+    pub(crate) fn read_write() -> Self {
+        Self {
+            read: true,
+            write: true,
+            ..Self::default()
+        }
+    }
+
+    /// Whether File Can Be Opened With The Options: Read OR Written, And Written If It Changes
+    pub(crate) fn is_valid(&self) -> bool {
+        let writable = self.write || self.append;
+        writable || self.read && !(self.truncate || self.create || self.create_new)
+    }
 }
 
+/// File, N Is The Capacity Of Extent Cache, No Cache By Default
 #[derive(Debug, Copy, Clone)]
-pub struct File<'a, T>
+pub struct File<'a, T, const N: usize = 0>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
     pub(crate) device: T,
     pub(crate) bpb: &'a BIOSParameterBlock,
     pub(crate) dir_cluster: u32,
+    /// Position Of The Short Item In Dir, Counted In Items From The Start Of Dir
+    pub(crate) item: usize,
     pub(crate) detail: Entry,
     pub(crate) fat: FAT<T>,
+    pub(crate) options: OpenOptions,
+    pub(crate) extents: ExtentCache<N>,
+    pub(crate) pos: usize,
+    /// New Clusters Which Get Data Before They Are Linked, (file_cluster, disk_cluster)
+    pub(crate) unlinked: Option<(u32, u32)>,
 }
 
-/// To Read File Per Sector By Iterator
+/// To Read File Per Sector By Iterator, Item Is Ok((sector, length)).
+/// Err(CorruptChain) Ends It If The Chain Is Broken OR Too Short For File Length
 pub struct ReadIter<'a, T>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
@@ -44,95 +168,217 @@ pub struct ReadIter<'a, T>
     need_count: usize,
 }
 
-impl<'a, T> File<'a, T>
+impl<'a, T, const N: usize> File<'a, T, N>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
-    /// Read File To Buffer, Return File Length
-    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
-        let length = self.detail.length().unwrap();
-        let spc = self.bpb.sector_per_cluster_usize();
-        let cluster_size = spc * BUFFER_SIZE;
-        let mut number_of_blocks = spc;
+    /// Use Extent Cache Which Holds M Extents At Most,
+    /// Then Seeking And Positional I/O Don't Need To Walk The Whole Chain
+    pub fn with_extent_cache<const M: usize>(self) -> File<'a, T, M> {
+        File::<T, M> {
+            device: self.device,
+            bpb: self.bpb,
+            dir_cluster: self.dir_cluster,
+            item: self.item,
+            detail: self.detail,
+            fat: self.fat,
+            options: self.options,
+            extents: ExtentCache::new(),
+            pos: self.pos,
+            unlinked: self.unlinked,
+        }
+    }
 
-        if buf.len() < length { return Err(FileError::BufTooSmall); }
+    /// Get Extent Cache
+    pub fn extent_cache(&self) -> &ExtentCache<N> {
+        &self.extents
+    }
 
-        let mut index = 0;
-        self.fat.map(|f| {
-            let offset = self.bpb.offset(f.current_cluster);
-            let end = if (length - index) < cluster_size {
-                let bytes_left = length % cluster_size;
-                number_of_blocks = get_needed_sector(bytes_left);
-                index + bytes_left
-            } else {
-                index + cluster_size
-            };
-            self.device.read(&mut buf[index..end],
-                             offset,
-                             number_of_blocks).unwrap();
-            index += cluster_size;
-        }).last();
+    /// Get File Length
+    pub fn length(&self) -> usize {
+        self.detail.length().unwrap()
+    }
 
-        Ok(length)
+    /// Get Runs Of Contiguous Sectors Of File, Item Is Ok((first_sector, sector_count)).
+    /// The Sectors Can Be Read Directly, Like DMA In Bootloader.
+    /// Err(CorruptChain) Ends The Runs If The Chain Is Broken OR Too Short For Length
+    pub fn extents(&self) -> SectorExtents<'a, T> {
+        SectorExtents {
+            bpb: self.bpb,
+            fat: FAT::new(self.detail.cluster(), self.device, self.bpb),
+            left_sectors: get_needed_sector(self.length()),
+            pending: None,
+        }
     }
 
-    /// Write Data To File, Using Append OR OverWritten
-    pub fn write(&mut self, buf: &[u8], write_type: WriteType) -> Result<(), FileError> {
-        let num_cluster = match write_type {
-            WriteType::OverWritten => self.num_cluster(buf.len()),
-            WriteType::Append => self.num_cluster(buf.len() + self.detail.length().unwrap())
+    /// Check If All Sectors Of File Are Contiguous, Err If The Chain Is Broken OR Too Short
+    pub fn is_contiguous(&self) -> Result<bool, FileError> {
+        let mut runs = 0;
+        for extent in self.extents() {
+            extent?;
+            runs += 1;
+        }
+        Ok(runs <= 1)
+    }
+
+    /// Seek To The Position, Return The New Position
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<usize, FileError> {
+        let length = self.length();
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => add_offset(length, n),
+            SeekFrom::Current(n) => add_offset(self.pos, n),
         };
 
-        match write_type {
-            WriteType::OverWritten => {
-                self.fat.map(|mut f| f.write(f.current_cluster, 0)).last();
-                self.write_blank_fat(num_cluster);
-                self._write(buf, &self.fat);
+        match pos {
+            Some(pos) if pos <= length => {
+                if pos < length { self.cluster_at(pos / self.cluster_size())?; }
+                self.pos = pos;
+                Ok(pos)
             }
-            WriteType::Append => {
-                let mut fat = self.fat;
-                let exist_fat = fat.count();
-                fat.find(|_| false);
+            _ => Err(FileError::OutOfRange)
+        }
+    }
 
-                let (new_cluster, index) = self.fill_left_sector(buf, fat.current_cluster);
-                if new_cluster {
-                    let buf = &buf[index..];
-                    let bl = self.fat.blank_cluster();
+    /// Read From The Position Which Seek Set, Return Read Length
+    pub fn read_next(&mut self, buf: &mut [u8]) -> Result<usize, FileError> {
+        let len = self.read_at(self.pos, buf)?;
+        self.pos += len;
+        Ok(len)
+    }
 
-                    fat.write(fat.current_cluster, bl);
-                    self.write_blank_fat(num_cluster - exist_fat);
-                    fat.refresh(bl);
+    /// Read From Offset Of File To Buffer, Return Read Length
+    pub fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, FileError> {
+        if !self.options.read { return Err(FileError::NotReadable); }
+        let length = self.length();
+        if offset >= length { return Ok(0); }
 
-                    self._write(buf, &fat);
-                }
+        let len = cmp::min(buf.len(), length - offset);
+        let mut data = [0; BUFFER_SIZE];
+        let mut done = 0;
+        while done < len {
+            let (address, sectors) = self.address_at(offset + done, (len - done) / BUFFER_SIZE)?;
+            let left = (offset + done) % BUFFER_SIZE;
+            if left == 0 && len - done >= BUFFER_SIZE {
+                let sectors = cmp::min(sectors, (len - done) / BUFFER_SIZE);
+                let end = done + sectors * BUFFER_SIZE;
+                self.device.read(&mut buf[done..end], address, sectors).unwrap();
+                done = end;
+            } else {
+                let n = cmp::min(BUFFER_SIZE - left, len - done);
+                self.device.read(&mut data, address, 1).unwrap();
+                buf[done..done + n].copy_from_slice(&data[left..left + n]);
+                done += n;
             }
         }
 
+        Ok(len)
+    }
+
+    /// Write Buffer To Offset Of File, The File Grows If Needed
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FileError> {
+        if !(self.options.write || self.options.append) { return Err(FileError::NotWritable); }
+        let length = self.length();
+        if offset > length { return Err(FileError::OutOfRange); }
+
+        let end = offset + buf.len();
+        let result = self.write_data(offset, buf, cmp::max(end, length));
+        let info = self.fat.update_fs_info();
+        Ok(result.and(info)?)
+    }
+
+    /// Read File To Buffer, Return File Length
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        let length = self.length();
+        if !self.options.read { return Err(FileError::NotReadable); }
+        if buf.len() < length { return Err(FileError::BufTooSmall); }
+
+        let mut file = *self;
+        file.read_at(0, &mut buf[0..length])
+    }
+
+    /// Write Data To File, Using Append OR OverWritten
+    /// If File Is Opened With append, Always Append
+    pub fn write(&mut self, buf: &[u8], write_type: WriteType) -> Result<(), FileError> {
+        if !(self.options.write || self.options.append) { return Err(FileError::NotWritable); }
+        let write_type = if self.options.append { WriteType::Append } else { write_type };
+
         match write_type {
-            WriteType::OverWritten => self.update_length(buf.len()),
-            WriteType::Append => self.update_length(buf.len() + self.detail.length().unwrap())
+            WriteType::OverWritten => {
+                let result = self.write_data(0, buf, buf.len())
+                    .and_then(|_| self.trim_chain(buf.len()));
+                let info = self.fat.update_fs_info();
+                Ok(result.and(info)?)
+            }
+            WriteType::Append => self.write_at(self.length(), buf),
+            WriteType::CopyOnWrite => {
+                let result = self.write_new_chain(buf);
+                let info = self.fat.update_fs_info();
+                Ok(result.and(info)?)
+            }
+        }
+    }
+
+    /// Reserve Clusters So The File Can Hold bytes Bytes, File Length Is Not Changed.
+    /// Later Writes Fill The Reserved Clusters Without Searching FAT.
+    /// If contiguous, The Reserved Clusters Are One Run, Following The File If Possible
+    pub fn preallocate(&mut self, bytes: usize, contiguous: bool) -> Result<(), FileError> {
+        if !(self.options.write || self.options.append) { return Err(FileError::NotWritable); }
+        let need = self.num_cluster(bytes);
+        let (exist, last) = self.chain_end()?;
+        if need <= exist { return Ok(()); }
+
+        let first = if contiguous {
+            self.fat.allocate_contiguous((need - exist) as u32, Some(last))
+                .map_err(|_| FileError::NoContiguousSpace)?
+        } else {
+            self.fat.allocate((need - exist) as u32, Some(last))?
         };
+        let result = self.link_chain(last, first);
+        let info = self.fat.update_fs_info();
+        Ok(result.and(info)?)
+    }
 
+    /// Close File, Release Unused Preallocated Clusters Unless keep_preallocated Is Set
+    pub fn close(mut self) -> Result<(), FileError> {
+        if !self.options.keep_preallocated {
+            let result = self.trim_chain(self.length());
+            let info = self.fat.update_fs_info();
+            result.and(info)?;
+        }
         Ok(())
     }
 
     /// Read Per Sector, Return ReadIter
-    pub fn read_per_sector(&self) -> ReadIter<T> {
+    pub fn read_per_sector(&self) -> ReadIter<'_, T> {
         let left_length = self.detail.length().unwrap();
         ReadIter::<T> {
             device: self.device,
             buffer: [0; BUFFER_SIZE],
             bpb: self.bpb,
-            fat: self.fat,
+            fat: FAT::new(self.detail.cluster(), self.device, self.bpb),
             left_length,
             read_count: 0,
             need_count: get_needed_sector(left_length),
         }
     }
 
+    /// Cut File To Zero Length, Only The First Cluster Is Kept
+    pub(crate) fn truncate(&mut self) -> Result<(), FATError> {
+        self.update_length(0)?;
+        let result = self.trim_chain(0);
+        let info = self.fat.update_fs_info();
+        result.and(info)
+    }
+
+    /// Get Bytes Of Cluster
+    fn cluster_size(&self) -> usize {
+        self.bpb.sector_per_cluster_usize() * BUFFER_SIZE
+    }
+
     /// Get Clusters The File Has
+    #[allow(clippy::manual_is_multiple_of)]
     fn num_cluster(&self, length: usize) -> usize {
-        let spc = self.bpb.sector_per_cluster_usize();
-        let cluster_size = spc * BUFFER_SIZE;
+        let cluster_size = self.cluster_size();
         if length % cluster_size != 0 {
             length / cluster_size + 1
         } else {
@@ -140,153 +386,283 @@ impl<'a, T> File<'a, T>
         }
     }
 
-    /// Write Buffer from one to another one
-    fn buf_write(&self, from: &[u8], value: usize, to: &mut [u8]) {
-        let index = value * BUFFER_SIZE;
-        let index_end = index + BUFFER_SIZE;
-        if from.len() < index_end {
-            to.copy_from_slice(&[0; BUFFER_SIZE]);
-            to[0..from.len() - index].copy_from_slice(&from[index..])
-        } else {
-            to.copy_from_slice(&from[index..index_end])
+    /// Whether File Is Empty AND Has No Cluster, Like Empty Files Other Systems Make
+    fn has_no_cluster(&self) -> bool {
+        self.detail.cluster() == 0 && self.length() == 0
+    }
+
+    /// Get Disk Cluster From Index Of Cluster In File.
+    /// Walk The Chain From The Furthest Cluster Extent Cache Knows
+    fn cluster_at(&mut self, file_cluster: usize) -> Result<u32, FATError> {
+        let file_cluster = file_cluster as u32;
+        if let Some(cluster) = self.extents.get(file_cluster) { return Ok(cluster); }
+
+        let (mut index, mut cluster) = match self.extents.start(file_cluster) {
+            Some(start) => start,
+            None => {
+                let first = self.detail.cluster();
+                if !self.fat.is_valid(first) { return Err(FATError::CorruptChain); }
+                self.extents.push(0, first);
+                (0, first)
+            }
+        };
+
+        while index < file_cluster {
+            cluster = match (self.next_cluster(cluster)?, self.unlinked) {
+                (Some(next), _) => next,
+                (None, Some((i, first))) if i == index + 1 => first,
+                (None, _) => return Err(FATError::CorruptChain)
+            };
+            index += 1;
+            self.extents.push(index, cluster);
+        }
+        Ok(cluster)
+    }
+
+    /// Get The Cluster After cluster In Chain, None If cluster Is The Last One
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FATError> {
+        match self.fat.read(cluster) {
+            FATEntry::Next(next) if self.fat.is_valid(next) => Ok(Some(next)),
+            FATEntry::EndOfChain => Ok(None),
+            _ => Err(FATError::CorruptChain)
         }
     }
 
-    /// Fill Left Sector
-    fn fill_left_sector(&self, buf: &[u8], cluster: u32) -> (bool, usize) {
+    /// Get Device Address Of File Offset, And Count Of Contiguous Sectors From It.
+    /// Following Clusters Are Merged While Fewer Than limit Sectors Are Found,
+    /// So One Device Call Can Transfer Them
+    fn address_at(&mut self, offset: usize, limit: usize) -> Result<(usize, usize), FATError> {
+        let cluster_size = self.cluster_size();
+        let index = offset / cluster_size;
+        let cluster = self.cluster_at(index)?;
+        let sector = offset % cluster_size / BUFFER_SIZE;
         let spc = self.bpb.sector_per_cluster_usize();
-        let length = self.detail.length().unwrap();
-        let get_used_sector = |len: usize| if len % (spc * BUFFER_SIZE) == 0 && length != 0 {
-            spc
-        } else {
-            len % (spc * BUFFER_SIZE) / BUFFER_SIZE
-        };
-        let left_start = length % BUFFER_SIZE;
-        let blank_size = BUFFER_SIZE - left_start;
 
-        let mut already_fill = 0;
-        let mut buf_has_left = true;
-        let mut index = 0;
-        let mut used_sector = get_used_sector(length);
+        let mut sectors = spc - sector;
+        if sectors < limit {
+            let max = ((limit - sectors).div_ceil(spc) + 1) as u32;
+            let len = self.fat.run_length(cluster, max);
+            for i in 1..len {
+                self.extents.push((index as u32) + i, cluster + i);
+            }
+            sectors += (len as usize - 1) * spc;
+        }
+        Ok((self.bpb.offset(cluster) + sector * BUFFER_SIZE, sectors))
+    }
+
+    /// Write Buffer To Offset Of File, The Chain Must Be Long Enough
+    fn write_sectors(&mut self, offset: usize, buf: &[u8]) -> Result<(), FATError> {
         let mut data = [0; BUFFER_SIZE];
-        let mut offset = self.bpb.offset(cluster) + used_sector * BUFFER_SIZE;
-
-        if left_start != 0 {
-            self.device.read(&mut data, offset, 1).unwrap();
-            if buf.len() <= blank_size {
-                data[left_start..left_start + buf.len()]
-                    .copy_from_slice(&buf[0..]);
-                buf_has_left = false;
+        let mut done = 0;
+        while done < buf.len() {
+            let (address, sectors) = self.address_at(offset + done, (buf.len() - done) / BUFFER_SIZE)?;
+            let left = (offset + done) % BUFFER_SIZE;
+            if left == 0 && buf.len() - done >= BUFFER_SIZE {
+                let sectors = cmp::min(sectors, (buf.len() - done) / BUFFER_SIZE);
+                let end = done + sectors * BUFFER_SIZE;
+                self.device.write(&buf[done..end], address, sectors).map_err(|_| FATError::WriteFailed)?;
+                done = end;
             } else {
-                data[left_start..].copy_from_slice(&buf[0..blank_size]);
-                already_fill = blank_size;
-                index = already_fill;
-                used_sector = get_used_sector(length + already_fill);
-                buf_has_left = true;
-            };
-            self.device.write(&data, offset, 1).unwrap();
-            offset = self.bpb.offset(cluster) + BUFFER_SIZE;
+                let n = cmp::min(BUFFER_SIZE - left, buf.len() - done);
+                self.device.read(&mut data, address, 1).unwrap();
+                data[left..left + n].copy_from_slice(&buf[done..done + n]);
+                self.device.write(&data, address, 1).map_err(|_| FATError::WriteFailed)?;
+                done += n;
+            }
         }
+        Ok(())
+    }
 
-        if buf_has_left {
-            let buf_needed_sector = get_needed_sector(buf.len() - already_fill);
-            let the_cluster_left_sector = spc - used_sector;
-            let num_sector = cmp::min(the_cluster_left_sector,
-                                      buf_needed_sector);
-            for s in 0..num_sector {
-                self.buf_write(&buf[index..], s, &mut data);
-                self.device.write(&data,
-                                  offset + s * BUFFER_SIZE,
-                                  1).unwrap();
-                index += BUFFER_SIZE;
-            }
+    /// Walk To The End Of Chain, Return Count Of Clusters And The Last One.
+    /// The Chain Can Be Longer Than Length Needs If Preallocated
+    fn chain_end(&mut self) -> Result<(usize, u32), FATError> {
+        if self.has_no_cluster() { return Ok((0, 0)); }
+        let mut index = cmp::max(1, self.num_cluster(self.length())) - 1;
+        let mut cluster = self.cluster_at(index)?;
+        while let Some(next) = self.next_cluster(cluster)? {
+            index += 1;
+            if index >= self.bpb.cluster_count() as usize { return Err(FATError::CorruptChain); }
+            cluster = next;
+            self.extents.push(index as u32, cluster);
+        }
+        Ok((index + 1, cluster))
+    }
 
-            if buf_needed_sector > the_cluster_left_sector { return (true, index); }
+    /// Write Buffer To Offset, Then Link New Clusters To The Chain, Then Set Length.
+    /// If Power Is Lost Before The Link, New Clusters Are Only Leaked
+    fn write_data(&mut self, offset: usize, buf: &[u8], length: usize) -> Result<(), FATError> {
+        let link = self.extend_chain(offset + buf.len())?;
+        let result = self.write_sectors(offset, buf);
+        self.unlinked = None;
+        if let Err(e) = result {
+            if let Some((_, first)) = link { self.unlink_chain(first); }
+            return Err(e);
         }
 
-        (false, 0)
+        if let Some((last, first)) = link { self.link_chain(last, first)?; }
+        if length != self.length() { self.update_length(length)?; }
+        Ok(())
     }
 
-    /// Update File Length
-    fn update_length(&mut self, length: usize) {
-        let fat = FAT::new(self.dir_cluster, self.device, self.bpb.fat1());
-        let mut iter = DirIter::new(self.device, fat, self.bpb);
-        iter.find(|d| {
-            !d.is_deleted() && !d.is_lfn() && d.cluster() == self.detail.cluster()
-        }).unwrap();
+    /// Make Sure The Chain Can Hold length Bytes, Preallocated Clusters Are Used First.
+    /// New Clusters Are Not Linked Yet, Return (last, first) To Link Them,
+    /// last Is 0 If The File Had No Cluster, Then detail Points To first In Memory Only
+    fn extend_chain(&mut self, length: usize) -> Result<Option<(u32, u32)>, FATError> {
+        let need = self.num_cluster(length);
+        if self.has_no_cluster() {
+            if need == 0 { return Ok(None); }
+            let first = self.fat.allocate(need as u32, None)?;
+            self.detail.set_cluster(first);
+            self.extents.clear();
+            return Ok(Some((0, first)));
+        }
+        let exist = cmp::max(1, self.num_cluster(self.length()));
+        if need <= exist { return Ok(None); }
+
+        let mut last = self.cluster_at(exist - 1)?;
+        for index in exist..need {
+            let next = match self.next_cluster(last)? {
+                Some(next) => next,
+                None => {
+                    let first = self.fat.allocate((need - index) as u32, Some(last))?;
+                    self.extents.push(index as u32, first);
+                    self.unlinked = Some((index as u32, first));
+                    return Ok(Some((last, first)));
+                }
+            };
+            self.extents.push(index as u32, next);
+            last = next;
+        }
+        Ok(None)
+    }
+
+    /// Write Buffer To A New Chain, Then Point Dir Item To It With One Sector Write,
+    /// Then Free The Old Chain. If Power Is Lost Between, Only Clusters Are Leaked
+    fn write_new_chain(&mut self, buf: &[u8]) -> Result<(), FATError> {
+        let count = cmp::max(1, self.num_cluster(buf.len()));
+        let first = self.fat.allocate(count as u32, None)?;
+
+        let old = self.detail;
+        self.detail.set_cluster(first);
+        self.extents.clear();
+        if let Err(e) = self.write_sectors(0, buf) {
+            self.detail = old;
+            self.extents.clear();
+            let _ = self.fat.free(first);
+            return Err(e);
+        }
 
+        self.detail = old;
+        self.switch_chain(first, buf.len())
+    }
+
+    /// Point Dir Item To The Chain From first Which Holds length Bytes,
+    /// Cluster And Length Change In One Sector Write. Then Free The Old Chain.
+    /// If The Dir Item Can't Be Written, The New Chain Is Freed Instead
+    pub(crate) fn switch_chain(&mut self, first: u32, length: usize) -> Result<(), FATError> {
+        let old = self.detail;
+        self.detail.set_cluster(first);
         self.detail.set_file_length(length);
-        iter.previous();
-        iter.update_item(&self.detail.bytes());
-        iter.update();
+        self.extents.clear();
+        if let Err(e) = self.update_entry() {
+            self.detail = old;
+            let _ = self.fat.free(first).and_then(|_| self.fat.update_fs_info());
+            return Err(e);
+        }
+        // the file has its new content now, a failure below only leaks the old chain
+        let result = self.fat.free(old.cluster());
+        let info = self.fat.update_fs_info();
+        self.fat = FAT::new(first, self.device, self.bpb);
+        result.and(info)
     }
 
-    /// Write Blank FAT
-    fn write_blank_fat(&mut self, num_cluster: usize) {
-        for n in 0..num_cluster {
-            let bl1 = self.fat.blank_cluster();
-            self.fat.write(bl1, 0x0FFFFFFF);
-            let bl2 = self.fat.blank_cluster();
-            if n != num_cluster - 1 {
-                self.fat.write(bl1, bl2);
+    /// Link New Chain From first After last, OR Point Dir Item To It If File Had No Cluster.
+    /// The New Chain Is Freed If Linking Fails
+    fn link_chain(&mut self, last: u32, first: u32) -> Result<(), FATError> {
+        if last != 0 {
+            if let Err(e) = self.fat.write(last, first) {
+                self.unlink_chain(first);
+                return Err(e);
             }
+            return Ok(());
+        }
+        self.detail.set_cluster(first);
+        if let Err(e) = self.update_entry() {
+            self.unlink_chain(first);
+            return Err(e);
         }
+        Ok(())
     }
 
-    /// Basic Write Function
-    fn _write(&self, buf: &[u8], fat: &FAT<T>) {
-        let spc = self.bpb.sector_per_cluster_usize();
-        let mut buf_write = [0; BUFFER_SIZE];
-        let mut write_count = get_needed_sector(buf.len());
-        let op = |start: usize, sectors: usize| -> &[u8] {
-            &buf[start * BUFFER_SIZE..(start + sectors) * BUFFER_SIZE]
+    /// Free New Chain From first Which Was Never Linked, As Far As Device Lets
+    fn unlink_chain(&mut self, first: u32) {
+        if self.detail.cluster() == first { self.detail.set_cluster(0); }
+        let _ = self.fat.free(first);
+        self.extents.clear();
+    }
+
+    /// Free Clusters After The Ones length Bytes Need, The First One Is Always Kept
+    fn trim_chain(&mut self, length: usize) -> Result<(), FATError> {
+        if self.has_no_cluster() { return Ok(()); }
+        let keep = cmp::max(1, self.num_cluster(length));
+        let last = self.cluster_at(keep - 1)?;
+        if let Some(next) = self.next_cluster(last)? {
+            self.extents.clear();
+            self.fat.write(last, END_OF_CHAIN)?;
+            self.fat.free(next)?;
+        }
+        Ok(())
+    }
+
+    /// Update File Length
+    fn update_length(&mut self, length: usize) -> Result<(), FATError> {
+        let old = self.detail.length().unwrap();
+        self.detail.set_file_length(length);
+        let result = self.update_entry();
+        if result.is_err() { self.detail.set_file_length(old); }
+        result
+    }
+
+    /// Write detail To The Dir Item At Its Position.
+    /// Err If The Item There Is Not The File's Any More, Like When It Was Deleted
+    fn update_entry(&mut self) -> Result<(), FATError> {
+        let fat = FAT::new(self.dir_cluster, self.device, self.bpb);
+        let mut iter = DirIter::new(self.device, fat, self.bpb);
+        let found = iter.seek(self.item) && match iter.next() {
+            Some(d) => !d.is_deleted() && !d.is_lfn() && d.sfn_bytes() == self.detail.sfn_bytes(),
+            None => false,
         };
+        if !found { return Err(FATError::CorruptChain); }
 
-        let mut w = 0;
-        fat.map(|f| {
-            let count = if write_count / spc > 0 {
-                write_count -= spc;
-                spc
-            } else {
-                write_count
-            };
+        iter.previous();
+        iter.update_item(&self.detail.bytes());
+        iter.update()
+    }
+}
 
-            let offset = self.bpb.offset(f.current_cluster);
-            if count == spc {
-                if (w + spc) * BUFFER_SIZE > buf.len() {
-                    self.buf_write(&buf, w, &mut buf_write);
-                    self.device.write(&buf_write,
-                                      offset,
-                                      1).unwrap();
-                } else {
-                    self.device.write(op(w, count),
-                                      offset,
-                                      count).unwrap();
-                }
-                w += count;
-            } else {
-                self.device.write(op(w, count - 1),
-                                  offset,
-                                  count - 1).unwrap();
-                w += count - 1;
-                self.buf_write(&buf, w, &mut buf_write);
-                self.device.write(&buf_write,
-                                  offset + (count - 1) * BUFFER_SIZE,
-                                  1).unwrap();
-            }
-        }).last();
+/// Add Signed Offset To Position
+fn add_offset(pos: usize, offset: isize) -> Option<usize> {
+    if offset < 0 {
+        pos.checked_sub(offset.unsigned_abs())
+    } else {
+        pos.checked_add(offset as usize)
     }
 }
 
 impl<'a, T> Iterator for ReadIter<'a, T>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
-    type Item = ([u8; BUFFER_SIZE], usize);
+    type Item = Result<([u8; BUFFER_SIZE], usize), FileError>;
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn next(&mut self) -> Option<Self::Item> {
         let spc = self.bpb.sector_per_cluster_usize();
         if self.read_count == self.need_count { return None; }
-        if self.read_count % spc == 0 { self.fat.next(); }
+        // the chain ends before file length, OR its first cluster is not in data region
+        if self.read_count % spc == 0 && self.fat.next().is_none() {
+            self.read_count = self.need_count;
+            return Some(Err(FileError::CorruptChain));
+        }
 
         let offset = self.bpb.offset(self.fat.current_cluster)
             + (self.read_count % spc) * BUFFER_SIZE;
@@ -295,11 +671,328 @@ impl<'a, T> Iterator for ReadIter<'a, T>
                          1).unwrap();
         self.read_count += 1;
 
-        Some(if self.read_count == self.need_count {
+        Some(Ok(if self.read_count == self.need_count {
             (self.buffer, self.left_length)
         } else {
             self.left_length -= BUFFER_SIZE;
             (self.buffer, BUFFER_SIZE)
-        })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dir::DirError;
+    use crate::ram::{Ram, power_cuts};
+    use crate::tool::read_le_u32;
+    use crate::volume::Volume;
+
+    const OLD: [u8; 1300] = [0x11; 1300];
+    const NEW: [u8; 2000] = [0x22; 2000];
+
+    /// Volume With OLD In "a.bin"
+    fn with_old() -> Ram {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file("a.bin").unwrap();
+        root.open_file("a.bin").unwrap().write(&OLD, WriteType::Append).unwrap();
+        ram
+    }
+
+    fn write(ram: Ram, buf: &[u8], write_type: WriteType) -> Result<(), FileError> {
+        let volume = Volume::new(ram);
+        let result = volume.root_dir().open_file("a.bin").unwrap().write(buf, write_type);
+        if let Err(e) = &result { assert!(matches!(e, FileError::WriteError)); }
+        result
+    }
+
+    fn content(ram: Ram, buf: &mut [u8]) -> usize {
+        let volume = Volume::new(ram);
+        let file = volume.root_dir().open_file("a.bin").unwrap();
+        file.read(buf).unwrap()
+    }
+
+    #[test]
+    fn append_power_cut() {
+        power_cuts(with_old, |ram| write(ram, &NEW, WriteType::Append), |ram, done| {
+            let mut buf = [0; 4096];
+            let len = content(ram, &mut buf);
+            assert_eq!(&buf[0..OLD.len()], &OLD[..]);
+            if done || len != OLD.len() {
+                assert_eq!(len, OLD.len() + NEW.len());
+                assert_eq!(&buf[OLD.len()..len], &NEW[..]);
+            }
+        });
+    }
+
+    #[test]
+    fn copy_on_write_power_cut() {
+        power_cuts(with_old, |ram| write(ram, &NEW, WriteType::CopyOnWrite), |ram, done| {
+            let mut buf = [0; 2048];
+            let len = content(ram, &mut buf);
+            if done { assert_eq!(&buf[0..len], &NEW[..]); }
+            assert!(buf[0..len] == OLD[..] || buf[0..len] == NEW[..]);
+        });
+    }
+
+    #[test]
+    fn overwrite_power_cut() {
+        // content is rewritten in place, only length AND chains are kept whole
+        power_cuts(with_old, |ram| write(ram, &[0x33; 100], WriteType::OverWritten), |ram, done| {
+            let mut buf = [0; 2048];
+            let len = content(ram, &mut buf);
+            if done { assert_eq!(&buf[0..len], &[0x33; 100][..]); }
+            assert!(len == OLD.len() || len == 100);
+        });
+    }
+
+    /// FSInfo, FAT AND Root Dir Sectors Of The Image
+    fn metadata(ram: Ram) -> std::vec::Vec<u8> {
+        let mut buf = std::vec![0; 65 * BUFFER_SIZE];
+        ram.read(&mut buf, 0, 65).unwrap();
+        buf
+    }
+
+    #[test]
+    fn disk_full_is_rolled_back() {
+        // 4032 clusters of one sector, root dir AND each new file hold one, FSInfo knows how many are free
+        let ram = Ram::format();
+        let mut fs_info = [0; BUFFER_SIZE];
+        ram.read(&mut fs_info, BUFFER_SIZE, 1).unwrap();
+        fs_info[0x1E8..0x1EC].copy_from_slice(&4031_u32.to_le_bytes());
+        ram.write(&fs_info, BUFFER_SIZE, 1).unwrap();
+
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file("big.bin").unwrap();
+        root.create_file("a.bin").unwrap();
+        root.open_file("big.bin").unwrap().write(&std::vec![0x44; 4000 * BUFFER_SIZE], WriteType::Append).unwrap();
+        let before = metadata(ram);
+        assert_eq!(read_le_u32(&before[BUFFER_SIZE + 0x1E8..]), 30);
+
+        let mut file = root.open_file("a.bin").unwrap();
+        assert!(matches!(file.write(&[0x55; 32 * BUFFER_SIZE], WriteType::Append), Err(FileError::DiskFull)));
+        assert_eq!(file.length(), 0);
+        assert!(metadata(ram) == before);
+
+        // 30 more clusters still fit, then a dir has no cluster left
+        file.write(&[0x55; 31 * BUFFER_SIZE], WriteType::Append).unwrap();
+        let before = metadata(ram);
+        assert_eq!(read_le_u32(&before[BUFFER_SIZE + 0x1E8..]), 0);
+        assert_eq!(root.create_dir("logs"), Err(DirError::DiskFull));
+        assert!(root.exist("logs").is_none());
+        assert!(metadata(ram) == before);
+
+        let report = volume.fsck::<4>(&mut [0; 256]).unwrap();
+        assert_eq!((report.broken, report.crosslinked, report.leaked), (0, 0, 0));
+    }
+
+    #[test]
+    fn open_options() {
+        let ram = with_old();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+
+        // nothing to do, OR a change without write
+        assert_eq!(root.open("a.bin", &OpenOptions::new()).err(), Some(DirError::InvalidOptions));
+        assert_eq!(root.open("a.bin", OpenOptions::new().read(true).truncate(true)).err(), Some(DirError::InvalidOptions));
+        assert_eq!(root.open("b.bin", OpenOptions::new().read(true).create(true)).err(), Some(DirError::InvalidOptions));
+
+        let mut file = root.open("a.bin", OpenOptions::new().read(true)).unwrap();
+        assert!(matches!(file.write(b"x", WriteType::Append), Err(FileError::NotWritable)));
+        assert!(matches!(file.preallocate(4096, false), Err(FileError::NotWritable)));
+        let mut file = root.open("a.bin", OpenOptions::new().write(true)).unwrap();
+        assert!(matches!(file.read_at(0, &mut [0; 10]), Err(FileError::NotReadable)));
+
+        // append ignores the write type
+        let mut file = root.open("a.bin", OpenOptions::new().append(true)).unwrap();
+        file.write(&[0x22; 100], WriteType::OverWritten).unwrap();
+        assert_eq!(file.length(), OLD.len() + 100);
+
+        // create opens an existing file as it is, truncate empties it
+        let file = root.open("a.bin", OpenOptions::new().write(true).create(true)).unwrap();
+        assert_eq!(file.length(), OLD.len() + 100);
+        let file = root.open("a.bin", OpenOptions::new().write(true).truncate(true)).unwrap();
+        assert_eq!(file.length(), 0);
+        assert_eq!(root.open_file("a.bin").unwrap().length(), 0);
+
+        assert_eq!(root.open("b.bin", OpenOptions::new().write(true)).err(), Some(DirError::NoMatchFile));
+        let mut file = root.open("b.bin", OpenOptions::new().read(true).write(true).create_new(true)).unwrap();
+        file.write(b"new", WriteType::Append).unwrap();
+        assert_eq!(file.read_at(0, &mut [0; 10]).unwrap(), 3);
+        assert_eq!(root.open("b.bin", OpenOptions::new().write(true).create_new(true)).err(), Some(DirError::AlreadyExists));
+        assert!(root.open("c.bin", OpenOptions::new().append(true).create(true)).is_ok());
+        assert!(root.exist("c.bin").is_some());
+
+        let report = volume.fsck::<4>(&mut [0; 256]).unwrap();
+        assert_eq!((report.broken, report.crosslinked, report.leaked), (0, 0, 0));
+    }
+
+    #[test]
+    fn preallocate_and_close() {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file("log.bin").unwrap();
+        let free = ram.free_clusters();
+
+        // the file holds one cluster, 9 more are reserved in one run
+        let mut file = root.open_file("log.bin").unwrap();
+        file.preallocate(10 * BUFFER_SIZE, true).unwrap();
+        assert_eq!((file.length(), ram.free_clusters()), (0, free - 9));
+        let first = file.detail.cluster();
+
+        // writes fill the reserved clusters
+        file.write(&[0x99; 3 * BUFFER_SIZE + 1], WriteType::Append).unwrap();
+        assert_eq!(ram.free_clusters(), free - 9);
+        assert_eq!(file.detail.cluster(), first);
+        assert!(file.is_contiguous().unwrap());
+        let report = volume.fsck::<4>(&mut [0; 256]).unwrap();
+        assert_eq!((report.broken, report.leaked), (0, 0));
+
+        // close keeps the 4 clusters which hold data
+        file.close().unwrap();
+        assert_eq!(ram.free_clusters(), free - 3);
+        let file = root.open_file("log.bin").unwrap();
+        assert_eq!((file.length(), file.detail.cluster()), (3 * BUFFER_SIZE + 1, first));
+        let report = volume.fsck::<4>(&mut [0; 256]).unwrap();
+        assert_eq!((report.broken, report.crosslinked, report.leaked), (0, 0, 0));
+    }
+
+    #[test]
+    fn preallocated_clusters_are_kept() {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        let options = *OpenOptions::new().write(true).create(true).keep_preallocated(true);
+        let mut file = root.open("log.bin", &options).unwrap();
+        let free = ram.free_clusters();
+        file.preallocate(8 * BUFFER_SIZE, false).unwrap();
+        file.close().unwrap();
+        assert_eq!(ram.free_clusters(), free - 7);
+
+        // less than the file has is nothing to do, a run longer than free space can't be found
+        let mut file = root.open("log.bin", &options).unwrap();
+        file.preallocate(BUFFER_SIZE, true).unwrap();
+        assert!(matches!(file.preallocate(5000 * BUFFER_SIZE, true), Err(FileError::NoContiguousSpace)));
+        assert!(matches!(file.preallocate(5000 * BUFFER_SIZE, false), Err(FileError::DiskFull)));
+        assert_eq!(ram.free_clusters(), free - 7);
+
+        // an open_file handle releases them
+        root.open_file("log.bin").unwrap().close().unwrap();
+        assert_eq!(ram.free_clusters(), free);
+    }
+
+    #[test]
+    fn broken_chain_is_an_error() {
+        let ram = with_old();
+        let volume = Volume::new(ram);
+        let root = volume.root_dir();
+        let file = root.open_file("a.bin").unwrap();
+
+        // OLD takes 3 clusters, the second links out of data region
+        let second = FAT::new(file.detail.cluster(), ram, file.bpb).nth(1).unwrap().current_cluster;
+        let mut fat = FAT::new(2, ram, file.bpb);
+        fat.write(second, 0x0FFF_FF00).unwrap();
+
+        let mut buf = [0; 2048];
+        assert!(matches!(file.read(&mut buf), Err(FileError::CorruptChain)));
+        let sectors: std::vec::Vec<_> = file.read_per_sector().collect();
+        assert!(matches!(sectors[..], [Ok(_), Ok(_), Err(FileError::CorruptChain)]));
+        let mut file = root.open_file("a.bin").unwrap();
+        assert_eq!(file.read_at(0, &mut buf[0..1024]).unwrap(), 1024);
+        assert!(matches!(file.read_at(1024, &mut buf), Err(FileError::CorruptChain)));
+        assert!(matches!(file.write(&NEW, WriteType::Append), Err(FileError::CorruptChain)));
+    }
+
+    /// Ram Which Logs Calls To Sectors After Root Dir, (write, first_sector, count)
+    #[derive(Copy, Clone)]
+    struct Logged {
+        ram: Ram,
+        calls: &'static core::cell::RefCell<std::vec::Vec<(bool, usize, usize)>>,
+    }
+
+    impl Logged {
+        fn new(ram: Ram) -> Self {
+            Self { ram, calls: std::boxed::Box::leak(std::boxed::Box::default()) }
+        }
+
+        fn take(&self) -> std::vec::Vec<(bool, usize, usize)> {
+            self.calls.take()
+        }
+
+        fn log(&self, write: bool, address: usize, count: usize) {
+            if address >= 65 * BUFFER_SIZE { self.calls.borrow_mut().push((write, address / BUFFER_SIZE, count)); }
+        }
+    }
+
+    impl BlockDevice for Logged {
+        type Error = ();
+
+        fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), ()> {
+            self.log(false, address, number_of_blocks);
+            self.ram.read(buf, address, number_of_blocks)
+        }
+
+        fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), ()> {
+            self.log(true, address, number_of_blocks);
+            self.ram.write(buf, address, number_of_blocks)
+        }
+    }
+
+    #[test]
+    fn contiguous_clusters_in_one_call() {
+        let device = Logged::new(Ram::format());
+        let volume = Volume::new(device);
+        let mut root = volume.root_dir();
+        root.create_file("a.bin").unwrap();
+        let mut file = root.open_file("a.bin").unwrap();
+        file.preallocate(40 * BUFFER_SIZE, true).unwrap();
+        // reserved clusters hold no data yet
+        assert!(file.extents().next().is_none());
+
+        let data: std::vec::Vec<u8> = (0..40 * BUFFER_SIZE).map(|i| (i / 7) as u8).collect();
+        device.take();
+        file.write(&data, WriteType::Append).unwrap();
+        let (first, count) = file.extents().next().unwrap().unwrap();
+        assert_eq!(count, 40);
+        assert_eq!(device.take(), [(true, first, 40)]);
+
+        let mut buf = std::vec![0; 40 * BUFFER_SIZE];
+        assert_eq!(file.read(&mut buf).unwrap(), data.len());
+        assert!(buf == data);
+        assert_eq!(device.take(), [(false, first, 40)]);
+
+        // partial sectors at both ends are read alone
+        assert_eq!(file.read_at(100, &mut buf[0..5 * BUFFER_SIZE]).unwrap(), 5 * BUFFER_SIZE);
+        assert!(buf[0..5 * BUFFER_SIZE] == data[100..100 + 5 * BUFFER_SIZE]);
+        assert_eq!(device.take(), [(false, first, 1), (false, first + 1, 4), (false, first + 5, 1)]);
+    }
+
+    #[test]
+    fn one_call_per_run() {
+        let device = Logged::new(Ram::format());
+        let volume = Volume::new(device);
+        let mut root = volume.root_dir();
+        root.create_file("a.bin").unwrap();
+        root.create_file("b.bin").unwrap();
+        for _ in 0..4 {
+            root.open_file("a.bin").unwrap().write(&[0xAA; 5 * BUFFER_SIZE], WriteType::Append).unwrap();
+            root.open_file("b.bin").unwrap().write(&[0xBB; 5 * BUFFER_SIZE], WriteType::Append).unwrap();
+        }
+
+        let file = root.open_file("a.bin").unwrap();
+        let runs: std::vec::Vec<(bool, usize, usize)> = file.extents().map(|run| {
+            let (first, count) = run.unwrap();
+            (false, first, count)
+        }).collect();
+        assert!(runs.len() > 1);
+        let mut buf = [0; 20 * BUFFER_SIZE];
+        device.take();
+        file.read(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xAA));
+        assert_eq!(device.take(), runs);
     }
 }
@@ -0,0 +1,242 @@
+use crate::upcase::upcase;
+use crate::tool::fnv1a;
+
+/// Where An Entry With A Name Is In Dir
+#[derive(Debug, Default, Copy, Clone)]
+struct Slot {
+    hash: u32,
+    /// Position Of The First Item Of The Entry, Counted In Items From The Start Of Dir
+    start: u16,
+    /// Count Of Items, LFN AND SFN, 0 If Slot Is Empty
+    len: u8,
+    /// Entry Was Deleted, Lookups Go On Past It
+    removed: bool,
+}
+
+/// In-RAM Map From Name Hash To Entry Position For One Dir, No Alloc.
+/// Holds N Names, An Entry With Long Name Takes Two For Its Long AND Short Name.
+/// Built When First Needed, Positions Are Checked On Disk Before They Are Trusted
+#[derive(Debug, Copy, Clone)]
+pub struct DirIndex<const N: usize> {
+    slots: [Slot; N],
+    /// Count Of Slots Used, Removed Ones Too
+    used: usize,
+    /// Count Of Slots Whose Entry Was Deleted
+    removed: usize,
+    built: bool,
+    /// Every Name Of Dir Is In The Index
+    complete: bool,
+    /// Position After The Last Item Of Dir
+    end: usize,
+}
+
+impl<const N: usize> DirIndex<N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: [Slot::default(); N],
+            used: 0,
+            removed: 0,
+            built: false,
+            complete: false,
+            end: 0,
+        }
+    }
+
+    /// Count Of Names In Index
+    pub fn len(&self) -> usize {
+        self.used - self.removed
+    }
+
+    /// Whether Index Holds No Name
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether Index Was Built, Lookups Scan Dir Until Then
+    pub fn is_built(&self) -> bool {
+        self.built
+    }
+
+    /// Whether Every Name Of Dir Fit In Index, Else Names Missing From It Are Looked For On Disk
+    pub fn is_complete(&self) -> bool {
+        self.built && self.complete
+    }
+
+    pub(crate) fn end(&self) -> usize {
+        self.end
+    }
+
+    pub(crate) fn set_end(&mut self, end: usize) {
+        self.end = end;
+    }
+
+    /// Empty Index, Ready To Be Filled
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new();
+        self.built = N != 0;
+        self.complete = true;
+    }
+
+    /// Add Name With hash Of Entry At start With len Items.
+    /// The First Slot Of A Deleted Entry On The Way Is Used Again
+    pub(crate) fn insert(&mut self, hash: u32, start: usize, len: usize) {
+        if !self.built { return; }
+        let first = hash as usize % N;
+        let free = (0..N).map(|i| (first + i) % N)
+            .find(|&i| self.slots[i].len == 0 || self.slots[i].removed);
+        let i = match free {
+            Some(i) if start <= u16::MAX as usize => i,
+            _ => {
+                self.complete = false;
+                return;
+            }
+        };
+
+        if self.slots[i].removed { self.removed -= 1; } else { self.used += 1; }
+        self.slots[i] = Slot {
+            hash,
+            start: start as u16,
+            len: len as u8,
+            removed: false,
+        };
+    }
+
+    /// Drop Names Of Entry At start
+    pub(crate) fn remove(&mut self, start: usize) {
+        for slot in self.slots.iter_mut().filter(|s| s.len != 0 && !s.removed && s.start as usize == start) {
+            slot.removed = true;
+            self.removed += 1;
+        }
+    }
+
+    /// Whether No Slot Is Empty While Some Hold Deleted Entries, Then Looking For
+    /// A Missing Name Goes Through Every Slot. Building Again Frees Them
+    pub(crate) fn is_worn(&self) -> bool {
+        self.used == N && self.removed != 0
+    }
+
+    /// Get (start, len) Of Entries Which May Have A Name With hash
+    pub(crate) fn get(&self, hash: u32) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let first = if N == 0 { 0 } else { hash as usize % N };
+        (0..N).map(move |i| self.slots[(first + i) % N])
+            .take_while(|s| s.len != 0)
+            .filter(move |s| !s.removed && s.hash == hash)
+            .map(|s| (s.start as usize, s.len as usize))
+    }
+}
+
+/// FNV-1a Over Uppercase UTF-16 Units, So Names Differing In Case Hash The Same
+pub(crate) fn name_hash(units: impl Iterator<Item = u16>) -> u32 {
+    fnv1a(units.map(upcase).flat_map(|unit| unit.to_le_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn built<const N: usize>() -> DirIndex<N> {
+        let mut index = DirIndex::new();
+        index.reset();
+        index
+    }
+
+    #[test]
+    fn collision() {
+        let mut index = built::<4>();
+        // 1 AND 5 land in the same slot, 5 goes on to the next one
+        index.insert(1, 10, 2);
+        index.insert(5, 20, 1);
+        index.insert(2, 30, 1);
+        assert_eq!(index.get(1).collect::<Vec<_>>(), [(10, 2)]);
+        assert_eq!(index.get(5).collect::<Vec<_>>(), [(20, 1)]);
+        assert_eq!(index.get(2).collect::<Vec<_>>(), [(30, 1)]);
+        assert_eq!(index.get(9).count(), 0);
+
+        // lookups go on past a deleted entry
+        index.remove(10);
+        assert_eq!(index.get(1).count(), 0);
+        assert_eq!(index.get(5).collect::<Vec<_>>(), [(20, 1)]);
+        assert_eq!(index.len(), 2);
+    }
+
+    #[test]
+    fn same_hash() {
+        let mut index = built::<8>();
+        index.insert(3, 1, 1);
+        index.insert(3, 7, 3);
+        assert_eq!(index.get(3).collect::<Vec<_>>(), [(1, 1), (7, 3)]);
+    }
+
+    #[test]
+    fn removed_slots_are_reused() {
+        let mut index = built::<4>();
+        for i in 0..4 { index.insert(i, i as usize * 2, 1); }
+        assert_eq!(index.len(), 4);
+        index.insert(9, 100, 1);
+        assert!(!index.is_complete());
+
+        let mut index = built::<4>();
+        for i in 0..4 { index.insert(i, i as usize * 2, 1); }
+        index.remove(2);
+        assert!(index.is_worn());
+        index.insert(5, 40, 2);
+        assert!(index.is_complete());
+        assert!(!index.is_worn());
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.get(5).collect::<Vec<_>>(), [(40, 2)]);
+        for i in [0, 2, 3].iter() { assert_eq!(index.get(*i).count(), 1); }
+
+        // names after the reused slot are still found
+        let mut index = built::<4>();
+        index.insert(0, 1, 1);
+        index.insert(4, 2, 1);
+        index.remove(1);
+        index.insert(8, 3, 1);
+        assert_eq!(index.get(8).collect::<Vec<_>>(), [(3, 1)]);
+        assert_eq!(index.get(4).collect::<Vec<_>>(), [(2, 1)]);
+        assert_eq!(index.get(0).count(), 0);
+    }
+
+    #[test]
+    fn worn_out() {
+        let mut index = built::<2>();
+        index.insert(0, 1, 1);
+        index.insert(1, 2, 1);
+        assert!(!index.is_worn());
+        index.remove(1);
+        index.remove(2);
+        assert!(index.is_worn());
+        assert!(index.is_empty());
+        // a missing name goes through every slot AND stops
+        assert_eq!(index.get(7).count(), 0);
+        index.reset();
+        assert!(!index.is_worn());
+    }
+
+    #[test]
+    fn far_start() {
+        let mut index = built::<4>();
+        index.insert(0, u16::MAX as usize + 1, 1);
+        assert!(!index.is_complete());
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn not_built() {
+        let mut index = DirIndex::<4>::new();
+        index.insert(0, 1, 1);
+        assert!(index.is_empty());
+        let mut index = built::<0>();
+        assert!(!index.is_built());
+        index.insert(0, 1, 1);
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn hash_ignores_case() {
+        let upper = name_hash("README.TXT".encode_utf16());
+        assert_eq!(name_hash("readme.txt".encode_utf16()), upper);
+        assert_ne!(name_hash("readme.txd".encode_utf16()), upper);
+    }
+}
@@ -0,0 +1,333 @@
+use core::cell::RefCell;
+use core::convert::TryInto;
+use block_device::BlockDevice;
+use crate::tool::{read_le_u32, fnv1a_from, FNV1A_START};
+use crate::volume::Volume;
+use crate::BUFFER_SIZE;
+
+/// Name Of The Hidden File Which Holds The Journal In Root Dir, Upper Case So It Is Stored As Is
+pub(crate) const JOURNAL_NAME: &str = "FSJOURNL.SYS";
+
+const MAGIC: &[u8; 8] = b"FAT32JNL";
+const HEADER_SIZE: usize = 16;
+
+/// Most Sectors One Journal Record Can Hold, Limited By Addresses In The Header Sector
+pub const MAX_JOURNAL_SECTORS: usize = (BUFFER_SIZE - HEADER_SIZE) / 8;
+
+#[derive(Copy, Clone)]
+struct Line {
+    address: usize,
+    data: [u8; BUFFER_SIZE],
+}
+
+struct Transaction<const N: usize> {
+    lines: [Line; N],
+    count: usize,
+    /// Whether Part Of The Transaction Was Committed Before commit()
+    split: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JournalError<E> {
+    Device(E),
+    /// The Changes Since Last Commit Did Not Fit In One Record,
+    /// They Reached Device In Parts AND Were Not Atomic
+    TransactionTooLarge,
+}
+
+/// Metadata Journal Between Volume And Device, No Alloc.
+/// Sector Writes Are Held In Memory Until commit(), Which Records Them In
+/// The Journal File First, Then Writes Them To Their Places.
+/// If Power Is Lost, Volume::new Replays A Complete Record OR Drops A Torn One,
+/// So Everything Between Two Commits Happens Completely OR Not At All,
+/// As Long As It Fits In N Sectors. Multi-Block Writes Are File Data, They Commit
+/// Held Sectors And Go To The Device At Once. A Transaction Bigger Than N Sectors
+/// OR With A Multi-Block Write Is Committed In Parts, commit() Then Gives TransactionTooLarge
+pub struct Journal<D, const N: usize>
+    where D: BlockDevice {
+    device: D,
+    address: usize,
+    tx: RefCell<Transaction<N>>,
+}
+
+impl<D, const N: usize> Journal<D, N>
+    where D: BlockDevice + Clone + Copy,
+          <D as BlockDevice>::Error: core::fmt::Debug {
+    /// Open Journal Of The Volume On Device, Leftover Record Is Replayed First.
+    /// None If Volume Has No Journal OR It Can't Hold N Sectors,
+    /// Make One With Volume::create_journal
+    pub fn new(device: D) -> Option<Self> {
+        let (address, sectors) = Volume::new(device).journal_area()?;
+        if N == 0 || N > MAX_JOURNAL_SECTORS || sectors < N + 1 { return None; }
+
+        Some(Self {
+            device,
+            address,
+            tx: RefCell::new(Transaction {
+                lines: [Line {
+                    address: 0,
+                    data: [0; BUFFER_SIZE],
+                }; N],
+                count: 0,
+                split: false,
+            }),
+        })
+    }
+
+    /// Count Of Sectors Held Since Last Commit
+    pub fn pending(&self) -> usize {
+        self.tx.borrow().count
+    }
+
+    /// Record Held Sectors In Journal, Then Write Them To Device.
+    /// Err(TransactionTooLarge) If Part Of The Changes Was Committed Earlier,
+    /// Everything Reached Device Anyway
+    pub fn commit(&self) -> Result<(), JournalError<D::Error>> {
+        let mut tx = self.tx.borrow_mut();
+        self.commit_transaction(&mut tx).map_err(JournalError::Device)?;
+        if tx.split {
+            tx.split = false;
+            return Err(JournalError::TransactionTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Drop Held Sectors, Device Stays As Of Last Commit
+    pub fn discard(&self) {
+        let mut tx = self.tx.borrow_mut();
+        tx.count = 0;
+        tx.split = false;
+    }
+
+    fn commit_transaction(&self, tx: &mut Transaction<N>) -> Result<(), D::Error> {
+        if tx.count == 0 { return Ok(()); }
+        let lines = &tx.lines[0..tx.count];
+
+        let mut header = [0; BUFFER_SIZE];
+        for (i, line) in lines.iter().enumerate() {
+            self.device.write(&line.data, self.address + (i + 1) * BUFFER_SIZE, 1)?;
+            let at = HEADER_SIZE + i * 8;
+            header[at..at + 8].copy_from_slice(&(line.address as u64).to_le_bytes());
+        }
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&(tx.count as u32).to_le_bytes());
+        let sum = checksum(&header, lines.iter().map(|l| &l.data));
+        header[12..16].copy_from_slice(&sum.to_le_bytes());
+
+        // the record is complete once the header is written
+        self.device.write(&header, self.address, 1)?;
+        for line in lines {
+            self.device.write(&line.data, line.address, 1)?;
+        }
+        clear(self.device, self.address)?;
+        tx.count = 0;
+        Ok(())
+    }
+}
+
+impl<D, const N: usize> BlockDevice for &Journal<D, N>
+    where D: BlockDevice + Clone + Copy,
+          <D as BlockDevice>::Error: core::fmt::Debug {
+    type Error = D::Error;
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        self.device.read(buf, address, number_of_blocks)?;
+        let tx = self.tx.borrow();
+        let end = address + number_of_blocks * BUFFER_SIZE;
+        for line in tx.lines[0..tx.count].iter().filter(|l| (address..end).contains(&l.address)) {
+            let at = line.address - address;
+            buf[at..at + BUFFER_SIZE].copy_from_slice(&line.data);
+        }
+        Ok(())
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let mut tx = self.tx.borrow_mut();
+        if number_of_blocks != 1 {
+            // freed clusters may be reused by this data, so the free must reach the device first
+            tx.split |= tx.count != 0;
+            self.commit_transaction(&mut tx)?;
+            return self.device.write(buf, address, number_of_blocks);
+        }
+
+        let i = match tx.lines[0..tx.count].iter().position(|l| l.address == address) {
+            Some(i) => i,
+            None => {
+                if tx.count == N {
+                    tx.split = true;
+                    self.commit_transaction(&mut tx)?;
+                }
+                tx.count += 1;
+                tx.count - 1
+            }
+        };
+        let line = &mut tx.lines[i];
+        line.address = address;
+        line.data.copy_from_slice(&buf[0..BUFFER_SIZE]);
+        Ok(())
+    }
+}
+
+/// Write Sectors Of A Complete Record To Their Places, Then Clear It.
+/// A Torn Record Was Never Applied, So It Is Only Cleared
+pub(crate) fn replay<D>(device: D, address: usize, sectors: usize) -> Result<(), D::Error>
+    where D: BlockDevice {
+    let mut header = [0; BUFFER_SIZE];
+    device.read(&mut header, address, 1)?;
+    if &header[0..8] != MAGIC { return Ok(()); }
+    let count = read_le_u32(&header[8..12]) as usize;
+    if count == 0 { return Ok(()); }
+    if count > MAX_JOURNAL_SECTORS || count + 1 > sectors { return clear(device, address); }
+
+    let mut sum = FNV1A_START;
+    let mut data = [0; BUFFER_SIZE];
+    for i in 0..count {
+        device.read(&mut data, address + (i + 1) * BUFFER_SIZE, 1)?;
+        sum = fnv1a_from(sum, data.iter().copied());
+    }
+    if fnv1a_from(sum, header[HEADER_SIZE..].iter().copied()) != read_le_u32(&header[12..16]) {
+        return clear(device, address);
+    }
+
+    for i in 0..count {
+        let at = HEADER_SIZE + i * 8;
+        let home = u64::from_le_bytes(header[at..at + 8].try_into().unwrap()) as usize;
+        device.read(&mut data, address + (i + 1) * BUFFER_SIZE, 1)?;
+        device.write(&data, home, 1)?;
+    }
+    clear(device, address)
+}
+
+/// Write Empty Header
+fn clear<D>(device: D, address: usize) -> Result<(), D::Error>
+    where D: BlockDevice {
+    let mut header = [0; BUFFER_SIZE];
+    header[0..8].copy_from_slice(MAGIC);
+    device.write(&header, address, 1)
+}
+
+/// Checksum Of Record Sectors AND Addresses In Header
+fn checksum<'a>(header: &[u8], sectors: impl Iterator<Item = &'a [u8; BUFFER_SIZE]>) -> u32 {
+    let sum = sectors.fold(FNV1A_START, |sum, data| fnv1a_from(sum, data.iter().copied()));
+    fnv1a_from(sum, header[HEADER_SIZE..].iter().copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::Ram;
+    use crate::volume::VolumeError;
+
+    /// Volume With A Journal Of 8 Sectors, Return Address Of The Journal Too
+    fn journaled() -> (Ram, usize) {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        volume.create_journal(8).unwrap();
+        (ram, volume.journal_area().unwrap().0)
+    }
+
+    /// Make A File AND A Dir Through The Journal, Cut Power After writes Of The Commit
+    fn crash(ram: Ram, writes: impl Fn(usize) -> usize) {
+        let journal = Journal::<_, 8>::new(ram).unwrap();
+        let volume = Volume::new(&journal);
+        let mut root = volume.root_dir();
+        root.create_file("a.txt").unwrap();
+        root.create_dir("logs").unwrap();
+
+        ram.cut_after(writes(journal.pending()));
+        assert_eq!(journal.commit(), Err(JournalError::Device(())));
+        ram.power_on();
+    }
+
+    fn has_changes(ram: Ram) -> bool {
+        let volume = Volume::new(ram);
+        let root = volume.root_dir();
+        match (root.exist("a.txt"), root.exist("logs")) {
+            (Some(_), Some(_)) => true,
+            (None, None) => false,
+            _ => panic!("commit was half done"),
+        }
+    }
+
+    fn is_clear(ram: Ram, address: usize) -> bool {
+        let mut header = [0; BUFFER_SIZE];
+        ram.read(&mut header, address, 1).unwrap();
+        // a new journal is all zero, a cleared one has only MAGIC
+        read_le_u32(&header[8..12]) == 0
+    }
+
+    #[test]
+    fn commit() {
+        let (ram, address) = journaled();
+        let journal = Journal::<_, 8>::new(ram).unwrap();
+        Volume::new(&journal).root_dir().create_file("a.txt").unwrap();
+        assert!(journal.pending() > 0);
+        assert!(Volume::new(ram).root_dir().exist("a.txt").is_none());
+
+        assert_eq!(journal.commit(), Ok(()));
+        assert_eq!(journal.pending(), 0);
+        assert!(is_clear(ram, address));
+        assert!(Volume::new(ram).root_dir().exist("a.txt").is_some());
+    }
+
+    #[test]
+    fn complete_record_is_replayed() {
+        // the header is written, no sector has reached its place
+        let (ram, address) = journaled();
+        crash(ram, |count| count + 1);
+        assert!(!is_clear(ram, address));
+        assert!(has_changes(ram));
+        assert!(is_clear(ram, address));
+
+        // some sectors have reached their places
+        let (ram, address) = journaled();
+        crash(ram, |count| count + 3);
+        assert!(has_changes(ram));
+        assert!(is_clear(ram, address));
+    }
+
+    #[test]
+    fn torn_record_is_dropped() {
+        // the header is not written
+        let (ram, address) = journaled();
+        crash(ram, |count| count);
+        assert!(!has_changes(ram));
+        assert!(is_clear(ram, address));
+
+        // a recorded sector doesn't match the checksum
+        let (ram, address) = journaled();
+        crash(ram, |count| count + 1);
+        ram.damage(address + BUFFER_SIZE + 100);
+        assert!(!has_changes(ram));
+        assert!(is_clear(ram, address));
+    }
+
+    #[test]
+    fn failed_replay_is_retried() {
+        let (ram, address) = journaled();
+        crash(ram, |count| count + 1);
+
+        ram.cut_after(0);
+        let volume = Volume::new(ram);
+        assert!(volume.replay_failed());
+        ram.power_on();
+        assert_eq!(volume.replay_journal(), Ok(()));
+        assert!(is_clear(ram, address));
+        assert!(has_changes(ram));
+    }
+
+    #[test]
+    fn create_journal_errors() {
+        let (ram, _) = journaled();
+        let volume = Volume::new(ram);
+        assert_eq!(volume.create_journal(8), Err(VolumeError::JournalExists));
+        assert_eq!(volume.create_journal(MAX_JOURNAL_SECTORS + 1), Err(VolumeError::JournalTooLarge));
+
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        ram.cut_after(0);
+        assert_eq!(volume.create_journal(8), Err(VolumeError::WriteFailed));
+        ram.power_on();
+        assert!(volume.journal_area().is_none());
+    }
+}
@@ -1,4 +1,6 @@
 #![no_std]
+#[cfg(test)]
+extern crate std;
 pub mod bpb;
 pub mod volume;
 pub mod tool;
@@ -6,6 +8,14 @@ pub mod dir;
 pub mod entry;
 pub mod file;
 pub mod fat;
+pub mod extent;
+pub mod cache;
+pub mod journal;
+pub mod upcase;
+pub mod codepage;
+pub mod index;
+#[cfg(test)]
+mod ram;
 
 #[cfg(feature = "512")]
 const BUFFER_SIZE: usize = 512;
@@ -43,7 +53,7 @@ mod fat32 {
     };
     use crate::dir::DirError;
     use crate::BUFFER_SIZE;
-    use crate::file::WriteType;
+    use crate::file::{WriteType, OpenOptions, SeekFrom};
 
     const GENERIC_READ: c_ulong = 1 << 31;
     const GENERIC_WRITE: c_ulong = 1 << 30;
@@ -215,7 +225,8 @@ mod fat32 {
         assert_eq!("测试一把梭", str::from_utf8(&buf[0..length.unwrap()]).unwrap());
 
         // read per sector
-        for (buffer, len) in file.read_per_sector() {
+        for sector in file.read_per_sector() {
+            let (buffer, len) = sector.unwrap();
             assert_eq!("测试一把梭", str::from_utf8(&buffer[0..len]).unwrap());
         }
 
@@ -236,8 +247,56 @@ mod fat32 {
         assert!(length.is_ok());
         assert_eq!([b'0'; 102410], buf[0..length.unwrap()]);
 
+        // test seek and positional I/O with extent cache
+        let mut file = file.with_extent_cache::<4>();
+        assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), 102400);
+        assert_eq!(file.read_next(&mut buf).unwrap(), 10);
+        assert_eq!([b'0'; 10], buf[0..10]);
+        file.write_at(102405, b"0123456789").unwrap();
+        assert_eq!(file.read_at(102405, &mut buf).unwrap(), 10);
+        assert_eq!(b"0123456789", &buf[0..10]);
+        assert_eq!(file.length(), 102415);
+        assert!(file.extent_cache().extents().len() > 0);
+
+        // test sector extents, the runs cover all sectors of file
+        let sectors: usize = file.extents().map(|extent| extent.unwrap().1).sum();
+        assert_eq!(sectors, (file.length() + BUFFER_SIZE - 1) / BUFFER_SIZE);
+        assert_eq!(file.is_contiguous().unwrap(), file.extents().count() == 1);
+
+        // test to open with OpenOptions, create_new fails if file exists
+        let opened = test_dir.open("options.txt", OpenOptions::new().write(true).create_new(true));
+        assert!(opened.is_ok());
+        let opened = test_dir.open("options.txt", OpenOptions::new().write(true).create_new(true));
+        assert_eq!(opened.err().unwrap(), DirError::AlreadyExists);
+        let opened = test_dir.open("跨簇测试1", OpenOptions::new().read(true));
+        assert_eq!(opened.err().unwrap(), DirError::IsADirectory);
+
+        // append mode always appends, read-only file can't be written
+        let mut file = test_dir.open("options.txt", OpenOptions::new().read(true).append(true)).unwrap();
+        file.write(b"hello", WriteType::OverWritten).unwrap();
+        file.write(b" world", WriteType::OverWritten).unwrap();
+        let length = file.read(&mut buf).unwrap();
+        assert_eq!(b"hello world", &buf[0..length]);
+        let mut file = test_dir.open("options.txt", OpenOptions::new().read(true)).unwrap();
+        assert!(file.write(b"!", WriteType::Append).is_err());
+
+        // preallocate contiguous clusters, length doesn't change, writes fill them
+        let mut file = test_dir.open("prealloc.log", OpenOptions::new().read(true).write(true).create(true)).unwrap();
+        file.preallocate(102400, true).unwrap();
+        assert_eq!(file.length(), 0);
+        file.write(&[b'1'; 1024], WriteType::Append).unwrap();
+        file.write(&[b'2'; 1024], WriteType::Append).unwrap();
+        assert!(file.is_contiguous().unwrap());
+        let length = file.read(&mut buf).unwrap();
+        assert_eq!(length, 2048);
+        file.close().unwrap();
+
+        // truncate
+        let file = test_dir.open("options.txt", OpenOptions::new().read(true).write(true).truncate(true)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+
         // test to delete
-        let delete_test_dir = root.delete_dir("test_dir");
+        let delete_test_dir = root.remove_dir_all("test_dir");
         assert!(delete_test_dir.is_ok());
     }
 }
@@ -0,0 +1,142 @@
+//! Block Device In RAM With A Small FAT32 Volume, For Unit Tests
+
+use core::cell::{Cell, RefCell};
+use std::boxed::Box;
+use std::vec;
+use std::vec::Vec;
+use block_device::BlockDevice;
+use crate::BUFFER_SIZE;
+use crate::bpb::BIOSParameterBlock;
+use crate::volume::Volume;
+
+const SECTORS: usize = 4096;
+const RESERVED: usize = 32;
+const SECTOR_PER_FAT: usize = 32;
+const FS_INFO: usize = 1;
+
+/// Leaked So Copies Of The Device Share One Image, Like Volume AND Dir Share A Card
+#[derive(Copy, Clone)]
+pub(crate) struct Ram {
+    image: &'static RefCell<Vec<u8>>,
+    /// Count Of Writes Before The Device Fails Like Power Was Lost
+    writes_left: &'static Cell<usize>,
+}
+
+impl Ram {
+    /// Make Empty FAT32 Volume Of 2 MiB, One FAT AND One Sector Per Cluster, Root Dir In Cluster 2
+    pub(crate) fn format() -> Self {
+        let mut image = vec![0; SECTORS * BUFFER_SIZE];
+
+        let boot = &mut image[0..BUFFER_SIZE];
+        boot[0x0B..0x0D].copy_from_slice(&(BUFFER_SIZE as u16).to_le_bytes());
+        boot[0x0D] = 1;
+        boot[0x0E..0x10].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+        boot[0x10] = 1;
+        boot[0x15] = 0xF8;
+        boot[0x20..0x24].copy_from_slice(&(SECTORS as u32).to_le_bytes());
+        boot[0x24..0x28].copy_from_slice(&(SECTOR_PER_FAT as u32).to_le_bytes());
+        boot[0x2C..0x30].copy_from_slice(&2_u32.to_le_bytes());
+        boot[0x30..0x32].copy_from_slice(&(FS_INFO as u16).to_le_bytes());
+        boot[0x42] = 0x29;
+        boot[0x43..0x47].copy_from_slice(&0x1234_5678_u32.to_le_bytes());
+        boot[0x47..0x52].copy_from_slice(b"NO NAME    ");
+        boot[0x52..0x5A].copy_from_slice(b"FAT32   ");
+        boot[0x1FE..0x200].copy_from_slice(&[0x55, 0xAA]);
+
+        let fs_info = &mut image[FS_INFO * BUFFER_SIZE..(FS_INFO + 1) * BUFFER_SIZE];
+        fs_info[0..4].copy_from_slice(&0x4161_5252_u32.to_le_bytes());
+        fs_info[0x1E4..0x1E8].copy_from_slice(&0x6141_7272_u32.to_le_bytes());
+        fs_info[0x1E8..0x1F0].copy_from_slice(&[0xFF; 8]);
+        fs_info[0x1FE..0x200].copy_from_slice(&[0x55, 0xAA]);
+
+        let fat = &mut image[RESERVED * BUFFER_SIZE..];
+        fat[0..4].copy_from_slice(&0x0FFF_FFF8_u32.to_le_bytes());
+        fat[4..8].copy_from_slice(&0x0FFF_FFFF_u32.to_le_bytes());
+        fat[8..12].copy_from_slice(&0x0FFF_FFFF_u32.to_le_bytes());
+
+        Self {
+            image: Box::leak(Box::new(RefCell::new(image))),
+            writes_left: Box::leak(Box::new(Cell::new(usize::MAX))),
+        }
+    }
+
+    /// BIOS Parameters Of The Image Made By format, For Tests Below Volume
+    pub(crate) fn bpb() -> BIOSParameterBlock {
+        BIOSParameterBlock {
+            byte_per_sector: BUFFER_SIZE as u16,
+            sector_per_cluster: 1,
+            reserved_sector: RESERVED as u16,
+            num_fat: 1,
+            total_sector: SECTORS as u32,
+            sector_per_fat: SECTOR_PER_FAT as u32,
+            root_cluster: 2,
+            fs_info_sector: FS_INFO as u16,
+            id: 0x1234_5678,
+            volume_label: *b"NO NAME    ",
+            file_system: *b"FAT32   ",
+        }
+    }
+
+    /// Fail Every Write After The Next count Ones
+    pub(crate) fn cut_after(&self, count: usize) {
+        self.writes_left.set(count);
+    }
+
+    /// Let Writes Reach The Image Again
+    pub(crate) fn power_on(&self) {
+        self.writes_left.set(usize::MAX);
+    }
+
+    /// Count Blank Entries In FAT, Read Straight From The Image
+    pub(crate) fn free_clusters(&self) -> usize {
+        let image = self.image.borrow();
+        let fat = &image[RESERVED * BUFFER_SIZE..(RESERVED + SECTOR_PER_FAT) * BUFFER_SIZE];
+        let end = SECTORS - RESERVED - SECTOR_PER_FAT + 2;
+        (2..end).filter(|&c| fat[c * 4..c * 4 + 4] == [0; 4]).count()
+    }
+
+    /// Flip Bits Of One Byte, Like A Sector Written Only In Part
+    pub(crate) fn damage(&self, address: usize) {
+        self.image.borrow_mut()[address] ^= 0xFF;
+    }
+}
+
+/// Run op On A Volume Made By setup, With Power Cut After 0, 1, 2.. Writes Until op Gets Through.
+/// Each Time Chains Must Stay Whole AND Not Shared, Leaked Clusters Are Allowed.
+/// check Gets The Volume After Power Is Back AND Whether op Got Through
+pub(crate) fn power_cuts<E, S, O, C>(setup: S, op: O, check: C)
+    where S: Fn() -> Ram,
+          O: Fn(Ram) -> Result<(), E>,
+          C: Fn(Ram, bool) {
+    for writes in 0.. {
+        let ram = setup();
+        ram.cut_after(writes);
+        let done = op(ram).is_ok();
+        ram.power_on();
+
+        let report = Volume::new(ram).fsck::<8>(&mut [0; 256]).unwrap();
+        assert_eq!((report.broken, report.crosslinked), (0, 0), "power cut after {} writes", writes);
+        check(ram, done);
+        if done { break; }
+    }
+}
+
+impl BlockDevice for Ram {
+    type Error = ();
+
+    fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        let len = number_of_blocks * BUFFER_SIZE;
+        buf[0..len].copy_from_slice(&self.image.borrow()[address..address + len]);
+        Ok(())
+    }
+
+    fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> Result<(), Self::Error> {
+        match self.writes_left.get() {
+            0 => return Err(()),
+            left => self.writes_left.set(left - 1),
+        }
+        let len = number_of_blocks * BUFFER_SIZE;
+        self.image.borrow_mut()[address..address + len].copy_from_slice(&buf[0..len]);
+        Ok(())
+    }
+}
@@ -2,6 +2,9 @@ use core::convert::TryInto;
 use core::str;
 use crate::BUFFER_SIZE;
 use crate::entry::NameType;
+use crate::dir::DirError;
+use crate::codepage::OemCodePage;
+use crate::upcase::{upcase, eq_units_ignore_case};
 
 pub(crate) fn is_fat32(value: &[u8]) -> bool {
     let file_system_str = str::from_utf8(&value[0..5]).unwrap();
@@ -18,51 +21,207 @@ pub(crate) fn read_le_u32(input: &[u8]) -> u32 {
     u32::from_le_bytes(int_bytes.try_into().unwrap())
 }
 
-pub(crate) fn is_illegal(chs: &str) -> bool {
-    let illegal_char = "\\/:*?\"<>|";
-    for ch in illegal_char.chars() {
-        if chs.contains(ch) {
-            return true;
-        }
+/// Device Names Windows Reserves, With OR Without Extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Check Name Like Windows Does, Return It Without Trailing Dots AND Spaces
+pub(crate) fn check_name(value: &str) -> Result<&str, DirError> {
+    let value = value.trim_end_matches(['.', ' ']);
+    if value.is_empty() { return Err(DirError::EmptyName); }
+    if value.contains(|ch: char| (ch as u32) < 0x20) { return Err(DirError::ControlChar); }
+    if value.contains(|ch| "\\/:*?\"<>|".contains(ch)) { return Err(DirError::IllegalChar); }
+
+    let base = value.split('.').next().unwrap().trim_end_matches(' ');
+    if RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(base)) {
+        return Err(DirError::ReservedName);
     }
-    false
+    Ok(value)
 }
 
+/// SFN If Name Fits 8.3 AND Each Part Is All Lowercase OR All Uppercase,
+/// Which NT Case Flags Can Keep, Else LFN Like Windows Does
 pub(crate) fn sfn_or_lfn(value: &str) -> NameType {
     let (name, extension) = match value.find('.') {
         Some(i) => (&value[0..i], &value[i + 1..]),
         None => (&value[0..], "")
     };
+    let one_case = |part: &str| !part.contains(|ch: char| ch.is_ascii_lowercase())
+        || !part.contains(|ch: char| ch.is_ascii_uppercase());
 
     if value.is_ascii()
-        && !value.contains(|ch: char| ch.is_ascii_uppercase())
-        && !value.contains(' ')
-        && !name.contains('.')
+        && !value.contains(|ch: char| ch == ' ' || SFN_ILLEGAL.contains(ch))
+        && !name.is_empty()
         && !extension.contains('.')
         && name.len() <= 8
-        && extension.len() <= 3 {
+        && extension.len() <= 3
+        && one_case(name)
+        && one_case(extension) {
         NameType::SFN
     } else {
         NameType::LFN
     }
 }
 
-pub(crate) fn get_count_of_lfn(value: &str) -> usize {
-    let num_char = value.chars().count();
-    if num_char % 13 == 0 { num_char / 13 } else { num_char / 13 + 1 }
+/// Chars Allowed In LFN But Not In SFN
+const SFN_ILLEGAL: &str = "+,;=[]";
+
+/// Basis Of Short Alias Made From Long Name
+pub(crate) struct BasisName {
+    /// Name AND Extension, Padded With Spaces
+    pub(crate) bytes: [u8; 11],
+    /// Count Of Name Bytes
+    len: usize,
+    /// Bit i Is Set If Name Byte i Starts A Char
+    starts: u8,
+    /// Set If Chars Were Dropped OR Replaced
+    pub(crate) lossy: bool,
 }
 
-pub(crate) fn get_lfn_index(value_str: &str, count: usize) -> usize {
-    let end = 13 * (count - 1);
-    let mut len = 0;
-    for (index, ch) in value_str.chars().enumerate() {
-        if (0..end).contains(&index) {
-            len += ch.len_utf8();
+impl BasisName {
+    /// Make Basis Like Windows: Uppercase, Drop Spaces AND Dots But The Last,
+    /// Replace Chars SFN Can't Hold With '_', Cut Name To 8 AND Extension To 3 Bytes
+    pub(crate) fn new(value: &str, code_page: &dyn OemCodePage) -> Self {
+        let trimmed = value.trim_start_matches('.');
+        let (name, extension) = match trimmed.rfind('.') {
+            Some(i) => (&trimmed[0..i], &trimmed[i + 1..]),
+            None => (trimmed, "")
+        };
+
+        let mut bytes = [0x20; 11];
+        let (mut len, mut starts, name_lossy) = Self::fill(&mut bytes[0..8], name, code_page);
+        let (_, _, ext_lossy) = Self::fill(&mut bytes[8..11], extension, code_page);
+        let mut lossy = name_lossy || ext_lossy || trimmed.len() != value.len();
+        if len == 0 {
+            bytes[0] = b'_';
+            len = 1;
+            starts = 1;
+            lossy = true;
+        }
+        Self { bytes, len, starts, lossy }
+    }
+
+    /// Encode part Into buf, Return Count Of Bytes, Char Starts AND Whether Anything Was Lost
+    fn fill(buf: &mut [u8], part: &str, code_page: &dyn OemCodePage) -> (usize, u8, bool) {
+        let (mut len, mut starts, mut lossy) = (0, 0, false);
+        for ch in part.chars() {
+            if ch == ' ' || ch == '.' {
+                lossy = true;
+                continue;
+            }
+            let ch = match ch as u32 {
+                unit @ 0..=0xFFFF => char::from_u32(upcase(unit as u16) as u32).unwrap_or(ch),
+                _ => ch,
+            };
+
+            let mut bytes = [0; 2];
+            let n = match code_page.encode(ch, &mut bytes) {
+                Some(n) if !SFN_ILLEGAL.contains(ch) && bytes[0] >= 0x20 => n,
+                _ => {
+                    lossy = true;
+                    bytes[0] = b'_';
+                    1
+                }
+            };
+            if len + n > buf.len() {
+                return (len, starts, true);
+            }
+            buf[len..len + n].copy_from_slice(&bytes[0..n]);
+            starts |= 1 << len;
+            len += n;
+        }
+        (len, starts, lossy)
+    }
+
+    /// Put "~tail" After As Much Of The Name As Fits, Without Splitting A Char
+    pub(crate) fn with_tail(&self, tail: u32) -> [u8; 11] {
+        let mut digits = [0; 7];
+        let mut count = 0;
+        let mut n = tail;
+        loop {
+            digits[count] = b'0' + (n % 10) as u8;
+            count += 1;
+            n /= 10;
+            if n == 0 { break; }
+        }
+
+        let mut prefix = self.len.min(7 - count);
+        while prefix > 0 && prefix < self.len && self.starts & (1 << prefix) == 0 { prefix -= 1; }
+
+        let mut bytes = self.bytes;
+        bytes[prefix..8].iter_mut().for_each(|b| *b = 0x20);
+        bytes[prefix] = b'~';
+        for i in 0..count {
+            bytes[prefix + 1 + i] = digits[count - 1 - i];
         }
+        bytes
     }
-    len
 }
 
+/// Match name Against DOS-Style pattern Case-Insensitively Like Windows,
+/// '*' Is Any Run Of Chars, '?' Is One Char AND "*.*" Is Everything.
+/// A '.' Followed Only By '*' Also Matches The End Of name, AND A '*' Before '.'
+/// Doesn't Run Past The Last '.' Of name, So "*." Is Names Without Extension
+pub(crate) fn wildcard_match(pattern: &str, name: &[u16]) -> bool {
+    let pattern = if pattern == "*.*" { "*" } else { pattern };
+    // count of UTF-16 units of the char at i
+    let width = |i: usize| if (0xD800..0xDC00).contains(&name[i]) && i + 1 < name.len() { 2 } else { 1 };
+    let last_dot = name.iter().rposition(|&unit| unit == u16::from(b'.')).unwrap_or(name.len());
+    let (mut p, mut n) = (0, 0);
+    // where the last '*' is in pattern, how much of name it took AND how much it can take
+    let mut star = None;
+
+    loop {
+        if n == name.len() {
+            let rest = &pattern[p..];
+            return rest.strip_prefix('.').unwrap_or(rest).chars().all(|ch| ch == '*');
+        }
+        let step = match pattern[p..].chars().next() {
+            Some('*') => {
+                let end = if pattern[p + 1..].starts_with('.') { last_dot } else { name.len() };
+                star = Some((p + 1, n, end));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some((1, width(n))),
+            Some(ch) => {
+                let mut units = [0; 2];
+                let units = ch.encode_utf16(&mut units);
+                let matched = n + units.len() <= name.len()
+                    && eq_units_ignore_case(units.iter().copied(), name[n..n + units.len()].iter().copied());
+                if matched { Some((ch.len_utf8(), units.len())) } else { None }
+            }
+            None => None,
+        };
+
+        match (step, star) {
+            (Some((p_step, n_step)), _) => {
+                p += p_step;
+                n += n_step;
+            }
+            (None, Some((star_p, star_n, end))) if star_n < end => {
+                // let the last '*' take one more char
+                let taken = star_n + width(star_n);
+                star = Some((star_p, taken, end));
+                p = star_p;
+                n = taken;
+            }
+            (None, _) => return false,
+        }
+    }
+}
+
+/// Get Count Of LFN Entries, Each Holds 13 UTF-16 Units
+pub(crate) fn get_count_of_lfn(value: &str) -> usize {
+    value.encode_utf16().count().div_ceil(13)
+}
+
+/// Longest Name In UTF-16 Units
+pub(crate) const MAX_NAME_UNITS: usize = 255;
+
 pub(crate) fn generate_checksum(value: &[u8]) -> u8 {
     let mut checksum = 0;
     for &i in value {
@@ -75,6 +234,20 @@ pub(crate) fn generate_checksum(value: &[u8]) -> u8 {
     checksum as u8
 }
 
+/// Start Of FNV-1a Hash
+pub(crate) const FNV1A_START: u32 = 0x811C_9DC5;
+
+/// FNV-1a Hash Of Bytes
+pub(crate) fn fnv1a(bytes: impl Iterator<Item = u8>) -> u32 {
+    fnv1a_from(FNV1A_START, bytes)
+}
+
+/// Go On With FNV-1a Hash sum Over More Bytes
+pub(crate) fn fnv1a_from(sum: u32, bytes: impl Iterator<Item = u8>) -> u32 {
+    bytes.fold(sum, |sum, b| (sum ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+#[allow(clippy::manual_is_multiple_of)]
 pub(crate) fn get_needed_sector(value: usize) -> usize {
     if value % BUFFER_SIZE != 0 {
         value / BUFFER_SIZE + 1
@@ -82,3 +255,148 @@ pub(crate) fn get_needed_sector(value: usize) -> usize {
         value / BUFFER_SIZE
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codepage::Cp437;
+
+    fn is_sfn(value: &str) -> bool {
+        matches!(sfn_or_lfn(value), NameType::SFN)
+    }
+
+    #[test]
+    fn sfn_or_lfn_like_windows() {
+        assert!(is_sfn("README.TXT"));
+        assert!(is_sfn("readme.txt"));
+        assert!(is_sfn("README.txt"));
+        assert!(is_sfn("ABCDEFGH.ABC"));
+        assert!(is_sfn("NOEXT"));
+        assert!(!is_sfn("ReadMe.txt"));
+        assert!(!is_sfn("ABCDEFGHI.TXT"));
+        assert!(!is_sfn("A.TEXT"));
+        assert!(!is_sfn("A.B.C"));
+        assert!(!is_sfn(".HIDDEN"));
+        assert!(!is_sfn("A B.TXT"));
+        assert!(!is_sfn("A+B.TXT"));
+        assert!(!is_sfn("CAFÉ.TXT"));
+    }
+
+    #[test]
+    fn basis_name() {
+        let basis = BasisName::new("Long File Name.Text", &Cp437);
+        assert_eq!(&basis.bytes, b"LONGFILETEX");
+        assert!(basis.lossy);
+        assert_eq!(&basis.with_tail(1), b"LONGFI~1TEX");
+        // longer tail takes more of the name
+        assert_eq!(&basis.with_tail(10), b"LONGF~10TEX");
+        assert_eq!(&basis.with_tail(123456), b"L~123456TEX");
+
+        let basis = BasisName::new("..a+b.tar.gz", &Cp437);
+        assert_eq!(&basis.bytes, b"A_BTAR  GZ ");
+        assert!(basis.lossy);
+        assert_eq!(&basis.with_tail(10), b"A_BTA~10GZ ");
+
+        let basis = BasisName::new("é.txt", &Cp437);
+        assert_eq!(&basis.bytes, b"\x90       TXT");
+        assert!(!basis.lossy);
+
+        let basis = BasisName::new("€.txt", &Cp437);
+        assert_eq!(&basis.bytes, b"_       TXT");
+        assert!(basis.lossy);
+    }
+
+    #[test]
+    fn check_name_like_windows() {
+        assert_eq!(check_name("file.txt"), Ok("file.txt"));
+        assert_eq!(check_name("file.txt. . "), Ok("file.txt"));
+        assert_eq!(check_name(" lead.txt"), Ok(" lead.txt"));
+        assert_eq!(check_name(".hidden"), Ok(".hidden"));
+        assert_eq!(check_name(""), Err(DirError::EmptyName));
+        assert_eq!(check_name(" . ."), Err(DirError::EmptyName));
+        assert_eq!(check_name("a\tb"), Err(DirError::ControlChar));
+        assert_eq!(check_name("a\u{1F}b"), Err(DirError::ControlChar));
+        for name in ["a/b", "a\\b", "a:b", "a*", "a?", "\"a\"", "a<b", "a>b", "a|b"].iter() {
+            assert_eq!(check_name(name), Err(DirError::IllegalChar));
+        }
+        for name in ["CON", "con", "Nul.txt", "COM1.tar.gz", "lpt9 .txt", "aux."].iter() {
+            assert_eq!(check_name(name), Err(DirError::ReservedName));
+        }
+        assert_eq!(check_name("COM10"), Ok("COM10"));
+        assert_eq!(check_name("CONSOLE.txt"), Ok("CONSOLE.txt"));
+        assert_eq!(check_name("my.con"), Ok("my.con"));
+    }
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let mut units = [0; 64];
+        let len = name.encode_utf16().zip(units.iter_mut()).map(|(unit, slot)| *slot = unit).count();
+        wildcard_match(pattern, &units[0..len])
+    }
+
+    #[test]
+    fn wildcard_stars() {
+        for name in ["a.txt", "noext", ".hidden", "a.b.c"].iter() {
+            assert!(matches("*.*", name));
+            assert!(matches("*", name));
+        }
+        assert!(matches("*.txt", "readme.TXT"));
+        assert!(!matches("*.txt", "readme.txt.bak"));
+        assert!(matches("fw*.bin", "FW-1.2.BIN"));
+        assert!(!matches("fw*.bin", "fw.bin.old"));
+        assert!(matches("**", ""));
+        assert!(!matches("a", ""));
+    }
+
+    #[test]
+    fn wildcard_backtracks() {
+        assert!(matches("*a*b", "xxaxxbxab"));
+        assert!(matches("*ab", "aaab"));
+        assert!(!matches("*a*b", "xxaxxbxa"));
+        assert!(matches("a*b*c", "abbbcbc"));
+        assert!(matches("*x?z", "xxyz"));
+    }
+
+    #[test]
+    fn wildcard_question_mark() {
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(!matches("??.txt", "a.txt"));
+        // one '?' takes a whole surrogate pair
+        assert!(matches("?.txt", "\u{1F600}.txt"));
+        assert!(matches("a?b", "a\u{10400}b"));
+        assert!(!matches("a??b", "a\u{10400}b"));
+        assert!(matches("\u{10428}*", "\u{10428}x"));
+    }
+
+    #[test]
+    fn wildcard_dots() {
+        // "*." is names without extension
+        assert!(matches("*.", "readme"));
+        assert!(!matches("*.", "readme.txt"));
+        assert!(matches("a*.", "abc"));
+        // '.' AND "*" after it match the end of name
+        assert!(matches("readme.*", "readme"));
+        assert!(matches("readme.", "readme"));
+        assert!(!matches("readme.?", "readme"));
+        assert!(matches("*.*", "readme"));
+        // '*' before '.' stops at the last '.'
+        assert!(matches("*.gz", "a.tar.gz"));
+        assert!(matches("*.tar.gz", "a.b.tar.gz"));
+    }
+
+    #[cfg(feature = "cp936")]
+    #[test]
+    fn tail_keeps_double_byte_chars_whole() {
+        use crate::codepage::Cp936;
+
+        let basis = BasisName::new("中文名字长.txt", &Cp936);
+        assert_eq!(&basis.bytes, b"\xD6\xD0\xCE\xC4\xC3\xFB\xD7\xD6TXT");
+        assert!(basis.lossy);
+        assert_eq!(&basis.with_tail(1), b"\xD6\xD0\xCE\xC4\xC3\xFB~1TXT");
+        // "~10" would cut the third char in half, so it goes
+        assert_eq!(&basis.with_tail(10), b"\xD6\xD0\xCE\xC4~10 TXT");
+
+        let basis = BasisName::new("a中文名字.txt", &Cp936);
+        assert_eq!(&basis.with_tail(1), b"A\xD6\xD0\xCE\xC4~1 TXT");
+    }
+}
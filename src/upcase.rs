@@ -0,0 +1,220 @@
+/// How A Range Of Lowercase Code Units Maps To Uppercase
+#[derive(Copy, Clone)]
+enum Fold {
+    /// Add Delta To Every Code Unit
+    Delta(i32),
+    /// Upper AND Lower Alternate, The Range Starts With Upper
+    Pairs,
+}
+
+/// Uppercase Table Like The One Windows Uses For File Names, One Code Unit To One.
+/// Sorted By First Code Unit, (first, last, fold)
+const UPCASE: &[(u16, u16, Fold)] = &[
+    (0x0061, 0x007A, Fold::Delta(-32)),
+    (0x00B5, 0x00B5, Fold::Delta(743)),
+    (0x00E0, 0x00F6, Fold::Delta(-32)),
+    (0x00F8, 0x00FE, Fold::Delta(-32)),
+    (0x00FF, 0x00FF, Fold::Delta(121)),
+    (0x0100, 0x012F, Fold::Pairs),
+    (0x0132, 0x0137, Fold::Pairs),
+    (0x0139, 0x0148, Fold::Pairs),
+    (0x014A, 0x0177, Fold::Pairs),
+    (0x0179, 0x017E, Fold::Pairs),
+    (0x0180, 0x0180, Fold::Delta(195)),
+    (0x0182, 0x0185, Fold::Pairs),
+    (0x0187, 0x0188, Fold::Pairs),
+    (0x018B, 0x018C, Fold::Pairs),
+    (0x0191, 0x0192, Fold::Pairs),
+    (0x0195, 0x0195, Fold::Delta(97)),
+    (0x0198, 0x0199, Fold::Pairs),
+    (0x019A, 0x019A, Fold::Delta(163)),
+    (0x019E, 0x019E, Fold::Delta(130)),
+    (0x01A0, 0x01A5, Fold::Pairs),
+    (0x01A7, 0x01A8, Fold::Pairs),
+    (0x01AC, 0x01AD, Fold::Pairs),
+    (0x01AF, 0x01B0, Fold::Pairs),
+    (0x01B3, 0x01B6, Fold::Pairs),
+    (0x01B8, 0x01B9, Fold::Pairs),
+    (0x01BC, 0x01BD, Fold::Pairs),
+    (0x01BF, 0x01BF, Fold::Delta(56)),
+    (0x01C5, 0x01C5, Fold::Delta(-1)),
+    (0x01C6, 0x01C6, Fold::Delta(-2)),
+    (0x01C8, 0x01C8, Fold::Delta(-1)),
+    (0x01C9, 0x01C9, Fold::Delta(-2)),
+    (0x01CB, 0x01CB, Fold::Delta(-1)),
+    (0x01CC, 0x01CC, Fold::Delta(-2)),
+    (0x01CD, 0x01DC, Fold::Pairs),
+    (0x01DD, 0x01DD, Fold::Delta(-79)),
+    (0x01DE, 0x01EF, Fold::Pairs),
+    (0x01F2, 0x01F2, Fold::Delta(-1)),
+    (0x01F3, 0x01F3, Fold::Delta(-2)),
+    (0x01F4, 0x01F5, Fold::Pairs),
+    (0x01F8, 0x021F, Fold::Pairs),
+    (0x0222, 0x0233, Fold::Pairs),
+    (0x023B, 0x023C, Fold::Pairs),
+    (0x0241, 0x0242, Fold::Pairs),
+    (0x0246, 0x024F, Fold::Pairs),
+    (0x0253, 0x0253, Fold::Delta(-210)),
+    (0x0254, 0x0254, Fold::Delta(-206)),
+    (0x0256, 0x0257, Fold::Delta(-205)),
+    (0x0259, 0x0259, Fold::Delta(-202)),
+    (0x025B, 0x025B, Fold::Delta(-203)),
+    (0x0260, 0x0260, Fold::Delta(-205)),
+    (0x0263, 0x0263, Fold::Delta(-207)),
+    (0x0268, 0x0268, Fold::Delta(-209)),
+    (0x0269, 0x0269, Fold::Delta(-211)),
+    (0x026F, 0x026F, Fold::Delta(-211)),
+    (0x0272, 0x0272, Fold::Delta(-213)),
+    (0x0275, 0x0275, Fold::Delta(-214)),
+    (0x0280, 0x0280, Fold::Delta(-218)),
+    (0x0283, 0x0283, Fold::Delta(-218)),
+    (0x0288, 0x0288, Fold::Delta(-218)),
+    (0x0289, 0x0289, Fold::Delta(-69)),
+    (0x028A, 0x028B, Fold::Delta(-217)),
+    (0x028C, 0x028C, Fold::Delta(-71)),
+    (0x0292, 0x0292, Fold::Delta(-219)),
+    (0x0370, 0x0373, Fold::Pairs),
+    (0x0376, 0x0377, Fold::Pairs),
+    (0x037B, 0x037D, Fold::Delta(130)),
+    (0x03AC, 0x03AC, Fold::Delta(-38)),
+    (0x03AD, 0x03AF, Fold::Delta(-37)),
+    (0x03B1, 0x03C1, Fold::Delta(-32)),
+    (0x03C2, 0x03C2, Fold::Delta(-31)),
+    (0x03C3, 0x03CB, Fold::Delta(-32)),
+    (0x03CC, 0x03CC, Fold::Delta(-64)),
+    (0x03CD, 0x03CE, Fold::Delta(-63)),
+    (0x03D8, 0x03EF, Fold::Pairs),
+    (0x03F2, 0x03F2, Fold::Delta(7)),
+    (0x03F7, 0x03F8, Fold::Pairs),
+    (0x03FA, 0x03FB, Fold::Pairs),
+    (0x0430, 0x044F, Fold::Delta(-32)),
+    (0x0450, 0x045F, Fold::Delta(-80)),
+    (0x0460, 0x0481, Fold::Pairs),
+    (0x048A, 0x04BF, Fold::Pairs),
+    (0x04C1, 0x04CE, Fold::Pairs),
+    (0x04CF, 0x04CF, Fold::Delta(-15)),
+    (0x04D0, 0x052F, Fold::Pairs),
+    (0x0561, 0x0586, Fold::Delta(-48)),
+    (0x1D79, 0x1D79, Fold::Delta(35332)),
+    (0x1D7D, 0x1D7D, Fold::Delta(3814)),
+    (0x1E00, 0x1E95, Fold::Pairs),
+    (0x1EA0, 0x1EFF, Fold::Pairs),
+    (0x1F00, 0x1F07, Fold::Delta(8)),
+    (0x1F10, 0x1F15, Fold::Delta(8)),
+    (0x1F20, 0x1F27, Fold::Delta(8)),
+    (0x1F30, 0x1F37, Fold::Delta(8)),
+    (0x1F40, 0x1F45, Fold::Delta(8)),
+    (0x1F51, 0x1F51, Fold::Delta(8)),
+    (0x1F53, 0x1F53, Fold::Delta(8)),
+    (0x1F55, 0x1F55, Fold::Delta(8)),
+    (0x1F57, 0x1F57, Fold::Delta(8)),
+    (0x1F60, 0x1F67, Fold::Delta(8)),
+    (0x1F70, 0x1F71, Fold::Delta(74)),
+    (0x1F72, 0x1F75, Fold::Delta(86)),
+    (0x1F76, 0x1F77, Fold::Delta(100)),
+    (0x1F78, 0x1F79, Fold::Delta(128)),
+    (0x1F7A, 0x1F7B, Fold::Delta(112)),
+    (0x1F7C, 0x1F7D, Fold::Delta(126)),
+    (0x1FB0, 0x1FB1, Fold::Delta(8)),
+    (0x1FD0, 0x1FD1, Fold::Delta(8)),
+    (0x1FE0, 0x1FE1, Fold::Delta(8)),
+    (0x1FE5, 0x1FE5, Fold::Delta(7)),
+    (0x214E, 0x214E, Fold::Delta(-28)),
+    (0x2170, 0x217F, Fold::Delta(-16)),
+    (0x2184, 0x2184, Fold::Delta(-1)),
+    (0x24D0, 0x24E9, Fold::Delta(-26)),
+    (0x2C30, 0x2C5E, Fold::Delta(-48)),
+    (0x2C61, 0x2C61, Fold::Delta(-1)),
+    (0x2C65, 0x2C65, Fold::Delta(-10795)),
+    (0x2C66, 0x2C66, Fold::Delta(-10792)),
+    (0x2C67, 0x2C6C, Fold::Pairs),
+    (0x2C72, 0x2C73, Fold::Pairs),
+    (0x2C75, 0x2C76, Fold::Pairs),
+    (0x2C80, 0x2CE3, Fold::Pairs),
+    (0x2D00, 0x2D25, Fold::Delta(-7264)),
+    (0xA640, 0xA66D, Fold::Pairs),
+    (0xA680, 0xA697, Fold::Pairs),
+    (0xA722, 0xA72F, Fold::Pairs),
+    (0xA732, 0xA76F, Fold::Pairs),
+    (0xA779, 0xA77C, Fold::Pairs),
+    (0xA77E, 0xA787, Fold::Pairs),
+    (0xA78B, 0xA78C, Fold::Pairs),
+    (0xA790, 0xA793, Fold::Pairs),
+    (0xA7A0, 0xA7A9, Fold::Pairs),
+    (0xFF41, 0xFF5A, Fold::Delta(-32)),
+];
+
+/// Get Uppercase Of UTF-16 Code Unit, Code Units Without One Are Kept.
+/// Surrogates Are Kept Too, Like Windows Does
+pub(crate) fn upcase(unit: u16) -> u16 {
+    let i = match UPCASE.binary_search_by(|&(first, _, _)| first.cmp(&unit)) {
+        Ok(i) => i,
+        Err(0) => return unit,
+        Err(i) => i - 1,
+    };
+
+    let (first, last, fold) = UPCASE[i];
+    if unit > last { return unit; }
+    match fold {
+        Fold::Delta(delta) => (unit as i32 + delta) as u16,
+        Fold::Pairs if (unit - first) % 2 == 1 => unit - 1,
+        Fold::Pairs => unit,
+    }
+}
+
+/// Compare UTF-16 Units Case-Insensitively As Windows Does, Code Unit By Code Unit
+pub(crate) fn eq_units_ignore_case<A, B>(a: A, b: B) -> bool
+    where A: Iterator<Item = u16>,
+          B: Iterator<Item = u16> {
+    a.map(upcase).eq(b.map(upcase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eq(a: &str, b: &str) -> bool {
+        eq_units_ignore_case(a.encode_utf16(), b.encode_utf16())
+    }
+
+    #[test]
+    fn ascii_and_latin() {
+        assert_eq!(upcase(u16::from(b'a')), u16::from(b'A'));
+        assert_eq!(upcase(u16::from(b'Z')), u16::from(b'Z'));
+        assert_eq!(upcase(u16::from(b'1')), u16::from(b'1'));
+        assert_eq!(upcase(0x00E9), 0x00C9);
+        assert_eq!(upcase(0x00FF), 0x0178);
+        // ß AND the multiplication sign have no single-unit uppercase
+        assert_eq!(upcase(0x00DF), 0x00DF);
+        assert_eq!(upcase(0x00F7), 0x00F7);
+    }
+
+    #[test]
+    fn pairs_and_other_scripts() {
+        assert_eq!(upcase(0x0101), 0x0100);
+        assert_eq!(upcase(0x0100), 0x0100);
+        assert_eq!(upcase(0x03B1), 0x0391);
+        assert_eq!(upcase(0x0430), 0x0410);
+        assert_eq!(upcase(0xFF41), 0xFF21);
+        assert_eq!(upcase(0x4E2D), 0x4E2D);
+    }
+
+    #[test]
+    fn surrogates_are_kept() {
+        for unit in [0xD801, 0xDC28, 0xDFFF].iter() {
+            assert_eq!(upcase(*unit), *unit);
+        }
+        // Deseret letters differ in case, Windows compares them as they are
+        assert!(!eq("\u{10428}", "\u{10400}"));
+    }
+
+    #[test]
+    fn names() {
+        assert!(eq("readme.txt", "README.TXT"));
+        assert!(eq("Ünïcödé", "ÜNÏCÖDÉ"));
+        assert!(eq("привет", "ПРИВЕТ"));
+        assert!(!eq("readme.txt", "readme.txt "));
+        assert!(!eq("straße", "STRASSE"));
+        assert!(eq("", ""));
+    }
+}
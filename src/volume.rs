@@ -12,9 +12,84 @@ use crate::tool::{
 };
 use crate::bpb::BIOSParameterBlock;
 use crate::BUFFER_SIZE;
-use crate::dir::Dir;
+use crate::index::DirIndex;
+use crate::dir::{
+    Dir,
+    DirError,
+};
 use crate::entry::Entry;
-use crate::fat::FAT;
+use crate::file::{
+    FileError,
+    WriteType,
+};
+use crate::cache::SectorCache;
+use crate::codepage::{
+    OemCodePage,
+    Cp437,
+};
+use crate::journal::{
+    self,
+    Journal,
+    JournalError,
+    JOURNAL_NAME,
+    MAX_JOURNAL_SECTORS,
+};
+use crate::fat::{
+    FAT,
+    FATEntry,
+    FATError,
+};
+
+/// Define VolumeError
+#[derive(Debug, PartialOrd, PartialEq)]
+pub enum VolumeError {
+    InvalidCluster,
+    ClusterInUse,
+    JournalExists,
+    JournalTooLarge,
+    NoContiguousSpace,
+    DiskFull,
+    CorruptChain,
+    WriteFailed,
+    BufTooSmall,
+}
+
+/// Problems Found By Volume::fsck, All 0 On A Clean Volume
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct FsckReport {
+    /// Files AND Dirs Checked
+    pub entries: usize,
+    /// Entries Whose Chain Is Broken OR Shorter Than Their Length, Dirs Which Can't Be Read
+    pub broken: usize,
+    /// Entries Whose Chain Runs Into Clusters Already Used, By Another Entry OR A Loop
+    pub crosslinked: usize,
+    /// Clusters Used In FAT But Held By No Entry, Only Counted If too_deep Is 0
+    pub leaked: usize,
+    /// Dirs Deeper Than The Walk Stack, Not Checked
+    pub too_deep: usize,
+}
+
+impl From<FATError> for VolumeError {
+    fn from(e: FATError) -> Self {
+        match e {
+            FATError::DiskFull => VolumeError::DiskFull,
+            FATError::CorruptChain => VolumeError::CorruptChain,
+            FATError::WriteFailed => VolumeError::WriteFailed,
+        }
+    }
+}
+
+impl From<FileError> for VolumeError {
+    fn from(e: FileError) -> Self {
+        match e {
+            FileError::NoContiguousSpace => VolumeError::NoContiguousSpace,
+            FileError::DiskFull => VolumeError::DiskFull,
+            FileError::CorruptChain => VolumeError::CorruptChain,
+            FileError::BufTooSmall | FileError::WriteError | FileError::NotReadable
+            | FileError::NotWritable | FileError::OutOfRange => VolumeError::WriteFailed,
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 pub struct Volume<T>
@@ -23,12 +98,18 @@ pub struct Volume<T>
 {
     device: T,
     bpb: BIOSParameterBlock,
+    code_page: &'static dyn OemCodePage,
+    /// Whether Journal Replay Failed When Mounted
+    replay_failed: bool,
 }
 
 impl<T> Volume<T>
     where T: BlockDevice + Clone + Copy,
           <T as BlockDevice>::Error: core::fmt::Debug {
-    /// Make volume from device which implement BlockDevice
+    /// Make volume from device which implement BlockDevice.
+    /// If Volume Has A Journal, Its Leftover Record Is Replayed,
+    /// Check replay_failed() If The Device Can Fail.
+    /// Temp File Left By Dir::replace_file Is Deleted
     pub fn new(device: T) -> Volume<T> {
         let mut buf = [0; 512];
         device.read(&mut buf, 0, 1).unwrap();
@@ -49,7 +130,7 @@ impl<T> Volume<T>
         // if not fat32 file system, panic
         if !is_fat32(&file_system) { panic!("not fat32 file system"); }
 
-        Volume::<T> {
+        let mut volume = Volume::<T> {
             device,
             bpb: BIOSParameterBlock {
                 byte_per_sector: bps,
@@ -59,10 +140,42 @@ impl<T> Volume<T>
                 total_sector: read_le_u32(&buf[0x20..0x24]),
                 sector_per_fat: read_le_u32(&buf[0x24..0x28]),
                 root_cluster: read_le_u32(&buf[0x2C..0x30]),
+                fs_info_sector: read_le_u16(&buf[0x30..0x32]),
                 id: read_le_u32(&buf[0x43..0x47]),
                 volume_label,
                 file_system,
             },
+            code_page: &Cp437,
+            replay_failed: false,
+        };
+
+        volume.replay_failed = volume.replay_journal().is_err();
+        // nothing else is written while the last commit may be half done
+        if volume.replay_failed { return volume; }
+        let mut root = volume.root_dir();
+        let _ = root.delete_replace_temp();
+        volume
+    }
+
+    /// Whether Leftover Journal Record Could Not Be Replayed When Mounted,
+    /// Then The Last Commit May Be Half Done. Try Again With replay_journal()
+    pub fn replay_failed(&self) -> bool {
+        self.replay_failed
+    }
+
+    /// Replay Leftover Journal Record, Nothing Is Done If Volume Has No Journal
+    pub fn replay_journal(&self) -> core::result::Result<(), T::Error> {
+        match self.journal_area() {
+            Some((address, sectors)) => journal::replay(self.device, address, sectors),
+            None => Ok(()),
+        }
+    }
+
+    /// Use Code Page For Short Names, CP437 By Default
+    pub fn with_code_page(self, code_page: &'static dyn OemCodePage) -> Volume<T> {
+        Volume::<T> {
+            code_page,
+            ..self
         }
     }
 
@@ -71,19 +184,193 @@ impl<T> Volume<T>
         str::from_utf8(&self.bpb.volume_label).unwrap()
     }
 
+    /// Mark Blank Cluster Bad, It Is Never Allocated Again.
+    /// Use It For Clusters Which Fail I/O Repeatedly
+    pub fn mark_bad_cluster(&self, cluster: u32) -> core::result::Result<(), VolumeError> {
+        let mut fat = FAT::new(self.bpb.root_cluster, self.device, &self.bpb);
+        if !fat.is_valid(cluster) { return Err(VolumeError::InvalidCluster); }
+        match fat.read(cluster) {
+            FATEntry::Free => {
+                fat.mark_bad(cluster)?;
+                Ok(fat.update_fs_info()?)
+            }
+            FATEntry::Bad => Ok(()),
+            _ => Err(VolumeError::ClusterInUse)
+        }
+    }
+
+    /// Read Every Blank Cluster, Mark The Ones Which Fail Bad. Return Count Of Marked,
+    /// Clusters Whose FAT Entry Can't Be Written Are Not Counted.
+    /// If write_verify, Also Write Test Patterns And Read Them Back, Data Of Blank Clusters Is Lost
+    pub fn surface_scan(&self, write_verify: bool) -> u32 {
+        let mut fat = FAT::new(self.bpb.root_cluster, self.device, &self.bpb);
+        let mut marked = 0;
+        let mut cluster = 2;
+        while let Some(blank) = fat.next_blank(cluster) {
+            if !self.is_good_cluster(blank, write_verify) && fat.mark_bad(blank).is_ok() {
+                marked += 1;
+            }
+            cluster = blank + 1;
+        }
+        let _ = fat.update_fs_info();
+        marked
+    }
+
+    /// Check If All Sectors Of Cluster Can Be Read, And Keep Written Data If write_verify
+    fn is_good_cluster(&self, cluster: u32, write_verify: bool) -> bool {
+        let mut buf = [0; BUFFER_SIZE];
+        let offset = self.bpb.offset(cluster);
+        (0..self.bpb.sector_per_cluster_usize()).all(|i| {
+            let address = offset + i * BUFFER_SIZE;
+            if write_verify {
+                [0x55, 0xAA].iter().all(|&pattern| {
+                    self.device.write(&[pattern; BUFFER_SIZE], address, 1).is_ok()
+                        && self.device.read(&mut buf, address, 1).is_ok()
+                        && buf.iter().all(|&b| b == pattern)
+                })
+            } else {
+                self.device.read(&mut buf, address, 1).is_ok()
+            }
+        })
+    }
+
+    /// Make Hidden Journal File In Root Dir, Which Holds capacity Sectors Per Record.
+    /// Open It With Journal::new, Other Systems See Only A Hidden File
+    pub fn create_journal(&self, capacity: usize) -> core::result::Result<(), VolumeError> {
+        if capacity == 0 || capacity > MAX_JOURNAL_SECTORS { return Err(VolumeError::JournalTooLarge); }
+        let dir_error = |e: DirError| match e {
+            DirError::AlreadyExists => VolumeError::JournalExists,
+            DirError::DiskFull => VolumeError::DiskFull,
+            DirError::CorruptChain => VolumeError::CorruptChain,
+            _ => VolumeError::WriteFailed,
+        };
+        let mut root = self.root_dir();
+        root.create_hidden_file(JOURNAL_NAME).map_err(dir_error)?;
+
+        let mut file = root.open_file(JOURNAL_NAME).map_err(dir_error)?;
+        let sectors = capacity + 1;
+        let result = file.preallocate(sectors * BUFFER_SIZE, true)
+            .and_then(|_| (0..sectors).try_for_each(|_| file.write(&[0; BUFFER_SIZE], WriteType::Append)))
+            .and_then(|_| file.is_contiguous())
+            .and_then(|contiguous| if contiguous { Ok(()) } else { Err(FileError::NoContiguousSpace) });
+        if let Err(e) = result {
+            root.delete_file(JOURNAL_NAME).map_err(dir_error)?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Check Every Chain In Dir Tree Without Changing Anything, D Is The Depth Of Stack Like walk.
+    /// used Holds One Bit Per Cluster, At Least (Count Of Clusters + 2) / 32 + 1 Words
+    pub fn fsck<const D: usize>(&self, used: &mut [u32]) -> core::result::Result<FsckReport, VolumeError> {
+        let mut fat = FAT::new(self.bpb.root_cluster, self.device, &self.bpb);
+        let end = self.bpb.cluster_count() + 2;
+        if used.len() < end as usize / 32 + 1 { return Err(VolumeError::BufTooSmall); }
+        used.iter_mut().for_each(|word| *word = 0);
+
+        let mut report = FsckReport::default();
+        // mark chain from cluster, Err If It Is Broken OR Runs Into A Used Cluster
+        let mut mark = |cluster: u32| -> core::result::Result<usize, bool> {
+            let mut chain = FAT::new(cluster, self.device, &self.bpb);
+            let mut count = 0;
+            for c in chain.by_ref().map(|f| f.current_cluster) {
+                let (word, bit) = (c as usize / 32, 1 << (c % 32));
+                if used[word] & bit != 0 { return Err(false); }
+                used[word] |= bit;
+                count += 1;
+            }
+            chain.check().map(|_| count).map_err(|_| true)
+        };
+
+        if mark(self.bpb.root_cluster).is_err() { report.broken += 1; }
+        let cluster_size = self.bpb.sector_per_cluster_usize() * BUFFER_SIZE;
+        let root = self.root_dir();
+        let mut walk = root.walk::<D>();
+        while let Some(item) = walk.next_entry() {
+            let d = match item {
+                Ok((_, _, d)) => d,
+                Err(DirError::TooDeep) => {
+                    report.too_deep += 1;
+                    continue;
+                }
+                Err(_) => {
+                    report.broken += 1;
+                    continue;
+                }
+            };
+            report.entries += 1;
+            let cluster = d.entry().cluster();
+            let need = if d.is_dir() { 1 } else { d.length().div_ceil(cluster_size) };
+            if cluster == 0 {
+                if need != 0 { report.broken += 1; }
+                continue;
+            }
+            match mark(cluster) {
+                Ok(count) if count >= need => (),
+                Err(false) => report.crosslinked += 1,
+                _ => report.broken += 1,
+            }
+        }
+
+        if report.too_deep == 0 {
+            fat.for_each_entry(|cluster, entry| {
+                let held = used[cluster as usize / 32] & (1 << (cluster % 32)) != 0;
+                match entry {
+                    FATEntry::Free | FATEntry::Bad => (),
+                    _ if !held => report.leaked += 1,
+                    _ => (),
+                }
+            });
+        }
+        Ok(report)
+    }
+
+    /// Get (address, sectors) Of Journal File, None If Volume Has No Journal.
+    /// A Journal Whose Chain Is Broken OR Not Contiguous Is Not Used, Neither Is A Visible File
+    pub(crate) fn journal_area(&self) -> Option<(usize, usize)> {
+        let root = self.root_dir();
+        if !root.exist(JOURNAL_NAME)?.is_hidden_system() { return None; }
+        let file = root.open_file(JOURNAL_NAME).ok()?;
+        let mut extents = file.extents();
+        let (sector, count) = extents.next()?.ok()?;
+        if extents.next().is_some() { return None; }
+        Some((sector * BUFFER_SIZE, count))
+    }
+
     /// Cd root dir, its Dir<T> Type
-    pub fn root_dir(&self) -> Dir<T> {
+    pub fn root_dir(&self) -> Dir<'_, T> {
         Dir::<T> {
             device: self.device,
             bpb: &self.bpb,
             detail: Entry::root_dir(self.bpb.root_cluster),
             fat: FAT::new(self.bpb.root_cluster,
                           self.device,
-                          self.bpb.fat1()),
+                          &self.bpb),
+            code_page: self.code_page,
+            index: DirIndex::new(),
         }
     }
 }
 
+impl<D, const N: usize> Volume<&SectorCache<D, N>>
+    where D: BlockDevice,
+          <D as BlockDevice>::Error: core::fmt::Debug {
+    /// Write Sectors Which Cache Holds Back To Device
+    pub fn flush(&self) -> core::result::Result<(), D::Error> {
+        self.device.flush()
+    }
+}
+
+impl<D, const N: usize> Volume<&Journal<D, N>>
+    where D: BlockDevice + Clone + Copy,
+          <D as BlockDevice>::Error: core::fmt::Debug {
+    /// Make Everything Done Since Last Commit Reach Device At Once,
+    /// Err(TransactionTooLarge) If It Did Not Fit In The Journal
+    pub fn commit(&self) -> core::result::Result<(), JournalError<D::Error>> {
+        self.device.commit()
+    }
+}
+
 /// implement Debug Display for Volume
 impl<T> Debug for Volume<T>
     where T: BlockDevice + Clone + Copy,
@@ -99,7 +386,165 @@ impl<T> Debug for Volume<T>
             .field("root_cluster", &self.bpb.root_cluster)
             .field("id", &self.bpb.id)
             .field("volume_label", &self.volume_label().trim())
-            .field("file_system", &"FAT32")
+            .field("file_system", &str::from_utf8(&self.bpb.file_system).unwrap().trim())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::Ram;
+
+    /// Volume With "y.wav", "z.txt" AND "music/x.wav" Of 1000 Bytes, Return First Cluster Of Each File
+    fn tree() -> (Ram, [u32; 3]) {
+        let ram = Ram::format();
+        let volume = Volume::new(ram);
+        let mut root = volume.root_dir();
+        root.create_file("y.wav").unwrap();
+        root.create_file("z.txt").unwrap();
+        root.create_dir("music").unwrap();
+        let mut music = root.cd("music").unwrap();
+        music.create_file("x.wav").unwrap();
+        music.open_file("x.wav").unwrap().write(&[0x77; 1000], WriteType::Append).unwrap();
+
+        let cluster = |dir: &Dir<'_, Ram>, name| dir.exist(name).unwrap().cluster();
+        (ram, [cluster(&root, "y.wav"), cluster(&root, "z.txt"), cluster(&music, "x.wav")])
+    }
+
+    /// Write FAT Entry Of cluster Without Going Through FAT<T>
+    fn set_fat(ram: Ram, cluster: u32, value: u32) {
+        let address = 32 * BUFFER_SIZE + cluster as usize * 4 / BUFFER_SIZE * BUFFER_SIZE;
+        let offset = cluster as usize * 4 % BUFFER_SIZE;
+        let mut buf = [0; BUFFER_SIZE];
+        ram.read(&mut buf, address, 1).unwrap();
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        ram.write(&buf, address, 1).unwrap();
+    }
+
+    fn fsck(ram: Ram) -> FsckReport {
+        Volume::new(ram).fsck::<4>(&mut [0; 256]).unwrap()
+    }
+
+    #[test]
+    fn fsck_clean() {
+        let (ram, _) = tree();
+        assert_eq!(fsck(ram), FsckReport { entries: 4, ..FsckReport::default() });
+        assert_eq!(Volume::new(ram).fsck::<4>(&mut [0; 100]), Err(VolumeError::BufTooSmall));
+
+        // music/x.wav is below a stack of 1 dir
+        let report = Volume::new(ram).fsck::<1>(&mut [0; 256]).unwrap();
+        assert_eq!((report.entries, report.too_deep, report.leaked), (3, 1, 0));
+    }
+
+    #[test]
+    fn fsck_crosslinked() {
+        // y.wav runs into the chain of x.wav, which is checked after it
+        let (ram, [y, _, x]) = tree();
+        set_fat(ram, y, x);
+        let report = fsck(ram);
+        assert_eq!((report.broken, report.crosslinked, report.leaked), (0, 1, 0));
+    }
+
+    #[test]
+    fn fsck_broken() {
+        // x.wav needs 2 clusters, its chain ends after one, the other is held by nobody
+        let (ram, [_, _, x]) = tree();
+        set_fat(ram, x, 0x0FFF_FFFF);
+        let report = fsck(ram);
+        assert_eq!((report.broken, report.crosslinked, report.leaked), (1, 0, 1));
+
+        // the chain of z.txt runs into a free cluster
+        let (ram, [_, z, _]) = tree();
+        set_fat(ram, z, 3000);
+        let report = fsck(ram);
+        assert_eq!((report.broken, report.crosslinked, report.leaked), (1, 0, 0));
+    }
+
+    #[test]
+    fn fsck_leaked() {
+        let (ram, _) = tree();
+        set_fat(ram, 3000, 3001);
+        set_fat(ram, 3001, 0x0FFF_FFFF);
+        let report = fsck(ram);
+        assert_eq!((report.entries, report.broken, report.crosslinked, report.leaked), (4, 0, 0, 2));
+    }
+
+    /// Ram Whose Sector unreadable Fails Reads AND Whose Sector weak Loses Written Data
+    #[derive(Copy, Clone)]
+    struct Worn {
+        ram: Ram,
+        unreadable: usize,
+        weak: usize,
+    }
+
+    impl BlockDevice for Worn {
+        type Error = ();
+
+        fn read(&self, buf: &mut [u8], address: usize, number_of_blocks: usize) -> core::result::Result<(), ()> {
+            if (address..address + number_of_blocks * BUFFER_SIZE).contains(&self.unreadable) { return Err(()); }
+            self.ram.read(buf, address, number_of_blocks)
+        }
+
+        fn write(&self, buf: &[u8], address: usize, number_of_blocks: usize) -> core::result::Result<(), ()> {
+            self.ram.write(buf, address, number_of_blocks)?;
+            if (address..address + number_of_blocks * BUFFER_SIZE).contains(&self.weak) { self.ram.damage(self.weak); }
+            Ok(())
+        }
+    }
+
+    /// Sector Address Of cluster In Ram::format
+    fn address(cluster: u32) -> usize {
+        (64 + cluster as usize - 2) * BUFFER_SIZE
+    }
+
+    fn entry(ram: Ram, cluster: u32) -> FATEntry {
+        FAT::new(2, ram, &Ram::bpb()).read(cluster)
+    }
+
+    #[test]
+    fn bad_clusters_are_skipped() {
+        let (ram, [y, _, _]) = tree();
+        let volume = Volume::new(ram);
+        let end = Ram::bpb().cluster_count() + 2;
+        assert_eq!(volume.mark_bad_cluster(1), Err(VolumeError::InvalidCluster));
+        assert_eq!(volume.mark_bad_cluster(end), Err(VolumeError::InvalidCluster));
+        assert_eq!(volume.mark_bad_cluster(y), Err(VolumeError::ClusterInUse));
+
+        // the next blank clusters are marked, a chain grows around them
+        let free = ram.free_clusters();
+        let next = FAT::new(2, ram, &Ram::bpb()).next_blank(2).unwrap();
+        for cluster in next..next + 3 { volume.mark_bad_cluster(cluster).unwrap(); }
+        volume.mark_bad_cluster(next).unwrap();
+        assert_eq!((ram.free_clusters(), entry(ram, next)), (free - 3, FATEntry::Bad));
+
+        let mut root = volume.root_dir();
+        root.create_file("new.bin").unwrap();
+        root.open_file("new.bin").unwrap().write(&[0x12; 4 * BUFFER_SIZE], WriteType::Append).unwrap();
+        let first = root.exist("new.bin").unwrap().cluster();
+        let chain: std::vec::Vec<u32> = FAT::new(first, ram, &Ram::bpb()).map(|f| f.current_cluster).collect();
+        assert_eq!(chain.len(), 4);
+        assert!(chain.iter().all(|c| !(next..next + 3).contains(c)));
+        assert_eq!(fsck(ram), FsckReport { entries: 5, ..FsckReport::default() });
+    }
+
+    #[test]
+    fn surface_scan() {
+        let (ram, _) = tree();
+        let worn = Worn { ram, unreadable: address(100) + 7, weak: address(200) + 3 };
+        let volume = Volume::new(worn);
+        let free = ram.free_clusters();
+
+        assert_eq!(volume.surface_scan(false), 1);
+        assert_eq!((entry(ram, 100), entry(ram, 200)), (FATEntry::Bad, FATEntry::Free));
+        assert_eq!(volume.surface_scan(true), 1);
+        assert_eq!((entry(ram, 200), ram.free_clusters()), (FATEntry::Bad, free - 2));
+        assert_eq!(volume.surface_scan(true), 0);
+
+        // a used cluster is never scanned
+        let root = address(2);
+        let volume = Volume::new(Worn { ram, unreadable: address(100), weak: root + 1 });
+        assert_eq!(volume.surface_scan(true), 0);
+        assert_eq!(fsck(ram), FsckReport { entries: 4, ..FsckReport::default() });
+    }
+}